@@ -0,0 +1,129 @@
+//! Reads the strategy token price published by mango-strategy in its NAV account
+//! (pda, seeds: `[strategy_id, "nav"]`) without depending on the program crate.
+use bytemuck::{Pod, Zeroable};
+use solana_program::{
+    account_info::AccountInfo, clock::Clock, hash::hash, msg, program_error::ProgramError,
+    pubkey::Pubkey, sysvar::Sysvar,
+};
+use std::convert::TryFrom;
+
+pub const NAV_PDA_SEED: &[u8] = b"nav";
+/// Strategy tokens and USDC both have 6 decimals
+pub const SHARE_PRICE_DECIMALS: u8 = 6;
+
+/// Same layout as `NavAccount` in mango-strategy, after the 8 byte discriminator
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Nav {
+    pub strategy_account: Pubkey,
+    /// native vault tokens per 10^SHARE_PRICE_DECIMALS strategy tokens
+    pub share_price: u64,
+    /// native vault tokens
+    pub tvl: u64,
+    pub supply: u64,
+    pub last_update_slot: u64,
+    pub last_update_ts: i64,
+    /// share price change on a 100% spot price move, in share_price units. 0 - fully hedged
+    pub confidence: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NavError {
+    InvalidOwner,
+    InvalidAccount,
+    /// never published or older than the allowed staleness
+    Stale,
+}
+
+/// `ProgramError::Custom` code of the first NavError, clear of Anchor and mango-strategy error codes
+pub const NAV_ERROR_OFFSET: u32 = 9000;
+
+impl From<NavError> for ProgramError {
+    fn from(e: NavError) -> Self {
+        ProgramError::Custom(NAV_ERROR_OFFSET + e as u32)
+    }
+}
+
+pub fn nav_address(program_id: &Pubkey, strategy_id: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[strategy_id.as_ref(), NAV_PDA_SEED], program_id).0
+}
+
+fn discriminator() -> [u8; 8] {
+    let mut discriminator = [0u8; 8];
+    discriminator.copy_from_slice(&hash(b"account:NavAccount").to_bytes()[..8]);
+    discriminator
+}
+
+/// Checks the owner and the account type. The caller should check `strategy_account`
+/// (or the address with `nav_address`) to make sure it is the expected strategy
+pub fn load_nav_unchecked(account: &AccountInfo, program_id: &Pubkey) -> Result<Nav, NavError> {
+    if account.owner != program_id {
+        return Err(NavError::InvalidOwner);
+    }
+    let data = account
+        .try_borrow_data()
+        .map_err(|_| NavError::InvalidAccount)?;
+    let size = 8 + std::mem::size_of::<Nav>();
+    if data.len() < size || data[..8] != discriminator() {
+        return Err(NavError::InvalidAccount);
+    }
+    Ok(bytemuck::pod_read_unaligned(&data[8..size]))
+}
+
+/// Fails if the NAV was last refreshed more than `max_staleness_slots` before `current_slot`
+pub fn load_nav_at_slot(
+    account: &AccountInfo,
+    program_id: &Pubkey,
+    current_slot: u64,
+    max_staleness_slots: u64,
+) -> Result<Nav, NavError> {
+    let nav = load_nav_unchecked(account, program_id)?;
+    if nav.last_update_slot == 0
+        || current_slot.saturating_sub(nav.last_update_slot) > max_staleness_slots
+    {
+        msg!(
+            "NAV stale: last update slot {}, current slot {}",
+            nav.last_update_slot,
+            current_slot
+        );
+        return Err(NavError::Stale);
+    }
+    Ok(nav)
+}
+
+/// On-chain, uses the Clock sysvar
+pub fn load_nav(
+    account: &AccountInfo,
+    program_id: &Pubkey,
+    max_staleness_slots: u64,
+) -> Result<Nav, ProgramError> {
+    let current_slot = Clock::get()?.slot;
+    Ok(load_nav_at_slot(
+        account,
+        program_id,
+        current_slot,
+        max_staleness_slots,
+    )?)
+}
+
+impl Nav {
+    /// Native vault tokens for `strategy_token_amount`, rounded down. None if it overflows u64
+    pub fn value(&self, strategy_token_amount: u64) -> Option<u64> {
+        u64::try_from(
+            strategy_token_amount as u128 * self.share_price as u128
+                / 10u128.pow(SHARE_PRICE_DECIMALS as u32),
+        )
+        .ok()
+    }
+
+    /// Value after a 100% adverse spot price move (share price minus confidence), rounded down.
+    /// None if it overflows u64
+    pub fn conservative_value(&self, strategy_token_amount: u64) -> Option<u64> {
+        u64::try_from(
+            strategy_token_amount as u128
+                * self.share_price.saturating_sub(self.confidence) as u128
+                / 10u128.pow(SHARE_PRICE_DECIMALS as u32),
+        )
+        .ok()
+    }
+}
@@ -1,6 +1,8 @@
-use crate::{mango_strategy, MarketInfo};
+use crate::{mango_strategy, BasisMode, ErrorCode, MarketInfo};
 use anchor_lang::prelude::*;
 use anchor_spl::token::{Mint, Token, TokenAccount};
+use fixed::types::I80F48;
+use std::convert::TryFrom;
 
 #[derive(Accounts)]
 #[instruction(bumps: Bumps)]
@@ -25,6 +27,42 @@ pub struct Initialize<'info> {
     )]
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
+    #[account(
+        init,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::HISTORY_PDA_SEED],
+        bump,
+        payer = deployer,
+        space = SharePriceHistory::LEN
+    )]
+    pub share_price_history: Box<Account<'info, SharePriceHistory>>,
+
+    #[account(
+        init,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+        payer = deployer,
+        space = NavAccount::LEN
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    #[account(
+        init_if_needed,
+        seeds=[mango_strategy::REGISTRY_PDA_SEED],
+        bump,
+        payer = deployer,
+        space = Registry::LEN
+    )]
+    pub registry: Box<Account<'info, Registry>>,
+
+    #[account(
+        init_if_needed,
+        seeds=[mango_strategy::REGISTRY_PDA_SEED, &(registry.strategy_count / RegistryPage::CAPACITY).to_le_bytes()],
+        bump,
+        payer = deployer,
+        space = RegistryPage::LEN
+    )]
+    pub registry_page: Box<Account<'info, RegistryPage>>,
+
     // Mango
     /// CHECK: mango account
     pub mango_program: AccountInfo<'info>,
@@ -88,11 +126,19 @@ pub struct Deposit<'info> {
     pub owner: AccountInfo<'info>,
 
     #[account(
+        mut,
         seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
         bump=bumps.strategy_account_bump,
     )]
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
     // Mango
     /// CHECK: mango account
     #[account(address = strategy_account.mango_program)]
@@ -103,7 +149,16 @@ pub struct Deposit<'info> {
     pub mango_group: AccountInfo<'info>,
 
     /// CHECK: mango account
-    #[account(mut)] // Mango checks for correct PDA
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
     pub mango_account: AccountInfo<'info>,
 
     /// CHECK: mango account
@@ -144,7 +199,33 @@ pub struct Deposit<'info> {
     #[account(mut, constraint = strategy_token_account.mint == strategy_token_mint.to_account_info().key())]
     pub strategy_token_account: Box<Account<'info, TokenAccount>>,
 
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = DepositReceipt::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::DEPOSIT_RECEIPT_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub deposit_receipt: Box<Account<'info, DepositReceipt>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::USER_POSITION_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::HISTORY_PDA_SEED],
+        bump,
+    )]
+    pub share_price_history: Box<Account<'info, SharePriceHistory>>,
+
     pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
     // Optional [writable] limits account
 }
 
@@ -159,11 +240,19 @@ pub struct Withdraw<'info> {
     pub owner: AccountInfo<'info>,
 
     #[account(
+        mut,
         seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
         bump=bumps.strategy_account_bump,
     )]
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
     // Mango
     /// CHECK: mango account
     #[account(address = strategy_account.mango_program)]
@@ -174,7 +263,16 @@ pub struct Withdraw<'info> {
     pub mango_group: AccountInfo<'info>,
 
     /// CHECK: mango account
-    #[account(mut)] // Mango already checks for correct PDA
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
     pub mango_account: AccountInfo<'info>,
 
     /// CHECK: mango account
@@ -214,6 +312,32 @@ pub struct Withdraw<'info> {
     #[account(mut, constraint = strategy_token_account.mint == strategy_token_mint.to_account_info().key())]
     pub strategy_token_account: Box<Account<'info, TokenAccount>>,
 
+    /// Created empty for holders who never deposited (received strategy tokens by transfer)
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = DepositReceipt::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::DEPOSIT_RECEIPT_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub deposit_receipt: Box<Account<'info, DepositReceipt>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::USER_POSITION_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::HISTORY_PDA_SEED],
+        bump,
+    )]
+    pub share_price_history: Box<Account<'info, SharePriceHistory>>,
+
     pub token_program: Program<'info, Token>,
     pub system_program: Program<'info, System>,
     // Optional [writable] limits account
@@ -230,11 +354,26 @@ pub struct AdjustPositionPerp<'info> {
     pub trigger_server: AccountInfo<'info>,
 
     #[account(
+        mut,
         seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
         bump=bumps.strategy_account_bump,
     )]
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    /// Strategy token
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
+
     // Mango
     /// CHECK: mango account
     #[account(address = strategy_account.mango_program)]
@@ -245,7 +384,16 @@ pub struct AdjustPositionPerp<'info> {
     pub mango_group: AccountInfo<'info>,
 
     /// CHECK: mango account
-    #[account(mut)] // Mango checks for correct PDA
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
     pub mango_account: AccountInfo<'info>,
 
     /// CHECK: mango account
@@ -280,6 +428,183 @@ pub struct AdjustPositionPerp<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct Snapshot<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::HISTORY_PDA_SEED],
+        bump,
+    )]
+    pub share_price_history: Box<Account<'info, SharePriceHistory>>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CheckDelta<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct ViewStats<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CancelPerpOrders<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.trigger_server_pk)]
+    pub trigger_server: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_asks: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_bids: AccountInfo<'info>,
+}
+
 #[derive(Accounts)]
 #[instruction(bumps: Bumps)]
 pub struct AdjustPositionSpot<'info> {
@@ -296,6 +621,20 @@ pub struct AdjustPositionSpot<'info> {
     )]
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    /// Strategy token
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
+
     // Mango
     /// CHECK: mango account
     #[account(address = strategy_account.mango_program)]
@@ -306,7 +645,16 @@ pub struct AdjustPositionSpot<'info> {
     pub mango_group: AccountInfo<'info>,
 
     /// CHECK: mango account
-    #[account(mut)] // Mango checks for correct PDA
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
     pub mango_account: AccountInfo<'info>,
 
     /// CHECK: mango account
@@ -365,6 +713,698 @@ pub struct AdjustPositionSpot<'info> {
     pub token_program: Program<'info, Token>,
 }
 
+/// Permissionless perp and spot trades paying a bounty in strategy tokens
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct PublicKeeper<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: anyone, receives the bounty
+    #[account(signer)]
+    pub caller: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    /// Strategy token
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
+
+    #[account(mut, constraint = caller_strategy_token_account.mint == strategy_token_mint.key())]
+    pub caller_strategy_token_account: Box<Account<'info, TokenAccount>>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut, address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut)] // refreshed by process_deposits
+    pub mango_cache: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_signer: AccountInfo<'info>,
+
+    // Perp
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_asks: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_bids: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_event_queue: AccountInfo<'info>,
+
+    // Spot
+    /// CHECK: mango account
+    pub serum_dex: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_open_orders: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_asks: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_bids: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_request_queue: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_event_queue: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_vault: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote_vault: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub serum_dex_signer: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub srm_vault: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+/// Deploys queued deposits, remaining accounts: Mango accounts 2..=mango_account_count,
+/// then [writable] pending deposit, depositor strategy token account and user position triples
+#[derive(Accounts)]
+pub struct ProcessDeposits<'info> {
+    pub keeper: PublicKeeper<'info>,
+
+    #[account(
+        mut,
+        seeds=[keeper.strategy_id.key().as_ref(), mango_strategy::DEPOSIT_QUEUE_PDA_SEED],
+        bump,
+    )]
+    pub deposit_queue: Box<Account<'info, DepositQueue>>,
+
+    #[account(
+        mut,
+        seeds=[keeper.strategy_id.key().as_ref(), mango_strategy::PENDING_VAULT_PDA_SEED],
+        bump,
+    )]
+    pub pending_vault_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds=[keeper.strategy_id.key().as_ref(), mango_strategy::HISTORY_PDA_SEED],
+        bump,
+    )]
+    pub share_price_history: Box<Account<'info, SharePriceHistory>>,
+
+    /// CHECK: spot token oracle, Mango checks it belongs to the group
+    pub oracle: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct QueueDeposit<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+
+    #[account(address = strategy_account.vault_token_mint)]
+    pub vault_token_mint: Box<Account<'info, Mint>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = DepositQueue::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::DEPOSIT_QUEUE_PDA_SEED],
+        bump,
+    )]
+    pub deposit_queue: Box<Account<'info, DepositQueue>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_VAULT_PDA_SEED],
+        bump,
+        token::mint = vault_token_mint,
+        token::authority = strategy_account,
+    )]
+    pub pending_vault_token_account: Box<Account<'info, TokenAccount>>,
+
+    /// Deposit token
+    #[account(
+        mut,
+        has_one = owner,
+        constraint = deposit_token_account.mint == strategy_account.vault_token_mint
+    )]
+    pub deposit_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = PendingDeposit::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_DEPOSIT_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub pending_deposit: Box<Account<'info, PendingDeposit>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = DepositReceipt::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::DEPOSIT_RECEIPT_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub deposit_receipt: Box<Account<'info, DepositReceipt>>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::USER_POSITION_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub user_position: Box<Account<'info, UserPosition>>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+    pub rent: Sysvar<'info, Rent>,
+    // Optional [writable] limits account
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CancelDeposit<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::DEPOSIT_QUEUE_PDA_SEED],
+        bump,
+    )]
+    pub deposit_queue: Box<Account<'info, DepositQueue>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_VAULT_PDA_SEED],
+        bump,
+    )]
+    pub pending_vault_token_account: Box<Account<'info, TokenAccount>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_DEPOSIT_PDA_SEED, owner.key().as_ref()],
+        bump,
+    )]
+    pub pending_deposit: Box<Account<'info, PendingDeposit>>,
+
+    #[account(
+        mut,
+        has_one = owner,
+        constraint = deposit_token_account.mint == strategy_account.vault_token_mint
+    )]
+    pub deposit_token_account: Box<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetDepositEpoch<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+/// For strategies initialized before the NAV account existed
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CreateNavAccount<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: anyone, pays the rent
+    #[account(signer, mut)]
+    pub payer: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        init,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+        payer = payer,
+        space = NavAccount::LEN
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Strategy accounts created before the fields after market_info were added
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct MigrateStrategyAccount<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: checked against the legacy account in the instruction, pays the rent
+    #[account(signer, mut)]
+    pub owner: AccountInfo<'info>,
+
+    /// CHECK: legacy layout, owner, length and discriminator checked in the instruction
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CreateMangoAccount<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut, address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut)] // Mango checks for correct PDA
+    pub mango_account: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct TransferUsdc<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: owner or trigger server
+    #[account(
+        signer,
+        constraint = authority.key() == strategy_account.owner || authority.key() == strategy_account.trigger_server_pk
+    )]
+    pub authority: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut, address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut)] // Mango checks the owner
+    pub from_mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut)] // Mango checks the owner
+    pub to_mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_vault: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_signer: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub from_spot_open_orders: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::VAULT_PDA_SEED],
+        bump
+    )]
+    pub vault_token_account: Box<Account<'info, TokenAccount>>,
+
+    pub token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct TriggerOrders<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: owner or trigger server, pays trigger order fees
+    #[account(
+        signer,
+        mut,
+        constraint = authority.key() == strategy_account.owner || authority.key() == strategy_account.trigger_server_pk
+    )]
+    pub authority: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    /// CHECK: system account, mango account delegate holding lamports for advanced orders
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::TRIGGER_DELEGATE_PDA_SEED],
+        bump,
+    )]
+    pub trigger_delegate: AccountInfo<'info>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_market: AccountInfo<'info>,
+    /// CHECK: mango account, pda of mango account
+    #[account(mut)]
+    pub advanced_orders: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub spot_open_orders: AccountInfo<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct ListTriggerOrders<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account, checked against mango account
+    pub advanced_orders: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct HarvestMngo<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut, address = strategy_account.trigger_server_pk)]
+    pub trigger_server: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    /// Strategy token
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut, address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &mango_strategy::MANGO_ACCOUNT_NUM.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_signer: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mngo_perp_vault: AccountInfo<'info>,
+
+    // MNGO/USDC spot
+    /// CHECK: mango account
+    pub serum_dex: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_market: AccountInfo<'info>,
+    /// CHECK: mango account, strategy spot market open orders
+    pub spot_open_orders: AccountInfo<'info>,
+    /// CHECK: mango account, created on the first harvest
+    #[account(mut)]
+    pub mngo_spot_open_orders: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_asks: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_bids: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_request_queue: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_event_queue: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub spot_base_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_vault: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub spot_quote_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_quote_vault: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub serum_dex_signer: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub srm_vault: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 #[instruction(bumps: Bumps)]
 pub struct SetLimits<'info> {
@@ -383,40 +1423,599 @@ pub struct SetLimits<'info> {
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
     #[account(
-        signer,
-        init_if_needed,
-        payer = owner,
-        space = LimitsAccount::LEN,
-        constraint = strategy_account.limits_account.is_none() || strategy_account.limits_account == Some(limits_account.key()))
-    ]
-    pub limits_account: Box<Account<'info, LimitsAccount>>,
+        signer,
+        init_if_needed,
+        payer = owner,
+        space = LimitsAccount::LEN,
+        constraint = strategy_account.limits_account.is_none() || strategy_account.limits_account == Some(limits_account.key()))
+    ]
+    pub limits_account: Box<Account<'info, LimitsAccount>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct DropLimits<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        close = owner,
+        constraint = strategy_account.limits_account == Some(limits_account.key()))
+    ]
+    pub limits_account: Box<Account<'info, LimitsAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetMinHoldingPeriod<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetOutflowLimit<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetMaxPerpPosition<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetMode<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetMinFundingRate<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetMaxBookDeviation<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct ResetCircuitBreaker<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct TripCircuitBreaker<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_bids: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_asks: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetMaxLeverage<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetKeeperConfig<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetHedgeConfig<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetFees<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct SetTimelockDelay<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct ProposeChange<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = PendingChange::LEN,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_CHANGE_PDA_SEED],
+        bump,
+    )]
+    pub pending_change: Box<Account<'info, PendingChange>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+/// Limits changes take the strategy limits account as the first remaining account
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct ExecuteChange<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_CHANGE_PDA_SEED],
+        bump,
+    )]
+    pub pending_change: Box<Account<'info, PendingChange>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CancelChange<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_CHANGE_PDA_SEED],
+        bump,
+    )]
+    pub pending_change: Box<Account<'info, PendingChange>>,
+}
+
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct DeprecateStrategy<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    #[account(
+        mut,
+        seeds=[mango_strategy::REGISTRY_PDA_SEED, &(strategy_account.registry_index / RegistryPage::CAPACITY).to_le_bytes()],
+        bump,
+    )]
+    pub registry_page: Box<Account<'info, RegistryPage>>,
+}
+
+/// Withdraws all tokens of the last Mango account to the owner and closes it, before close_strategy
+#[derive(Accounts)]
+#[instruction(bumps: Bumps)]
+pub struct CloseMangoAccount<'info> {
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    pub strategy_id: AccountInfo<'info>,
+
+    /// CHECK: This is not dangerous because we don't read or write from this account
+    #[account(signer, mut, address = strategy_account.owner)]
+    pub owner: AccountInfo<'info>,
+
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
+        bump=bumps.strategy_account_bump,
+    )]
+    pub strategy_account: Box<Account<'info, StrategyAccount>>,
+
+    /// Strategy token
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
 
-    pub system_program: Program<'info, System>,
+    // Mango
+    /// CHECK: mango account
+    #[account(address = strategy_account.mango_program)]
+    pub mango_program: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(mut, address = strategy_account.mango_group)]
+    pub mango_group: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    #[account(
+        mut,
+        seeds=[
+            mango_group.key().as_ref(),
+            strategy_account.key().as_ref(),
+            &strategy_account.mango_account_count.to_le_bytes(),
+        ],
+        bump,
+        seeds::program = mango_program.key(),
+    )]
+    pub mango_account: AccountInfo<'info>,
+
+    /// CHECK: mango account
+    pub mango_cache: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub mango_signer: AccountInfo<'info>,
+    /// CHECK: mango account, Mango checks for correct PDA ([mango_group, "DustAccount"])
+    #[account(mut)]
+    pub dust_account: AccountInfo<'info>,
+    /// CHECK: mango account, perp market for redeeming MNGO
+    pub mango_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mngo_perp_vault: AccountInfo<'info>,
+
+    // USDC
+    /// CHECK: mango account
+    pub mango_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mango_vault: AccountInfo<'info>,
+    /// Receives the USDC deposit (fees, dust)
+    #[account(
+        mut,
+        has_one = owner,
+        constraint = owner_token_account.mint == strategy_account.vault_token_mint
+    )]
+    pub owner_token_account: Box<Account<'info, TokenAccount>>,
+
+    // Spot token
+    /// CHECK: mango account
+    pub spot_base_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_base_vault: AccountInfo<'info>,
+    #[account(mut, has_one = owner)]
+    pub owner_base_token_account: Box<Account<'info, TokenAccount>>,
+
+    // MNGO
+    /// CHECK: mango account
+    pub mngo_root_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mngo_node_bank: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub mngo_vault: AccountInfo<'info>,
+    #[account(mut, has_one = owner)]
+    pub owner_mngo_token_account: Box<Account<'info, TokenAccount>>,
+
+    // Spot
+    /// CHECK: mango account
+    pub serum_dex: AccountInfo<'info>,
+    /// CHECK: mango account
+    pub spot_market: AccountInfo<'info>,
+    /// CHECK: mango account
+    #[account(mut)]
+    pub spot_open_orders: AccountInfo<'info>,
+
+    pub token_program: Program<'info, Token>,
+    // Optional [writable] advanced orders if trigger orders were used
+    // Optional MNGO spot market and [writable] MNGO open orders if harvest_mngo was used
 }
 
 #[derive(Accounts)]
 #[instruction(bumps: Bumps)]
-pub struct DropLimits<'info> {
+pub struct CloseStrategy<'info> {
     /// CHECK: This is not dangerous because we don't read or write from this account
     pub strategy_id: AccountInfo<'info>,
 
     /// CHECK: This is not dangerous because we don't read or write from this account
-    #[account(signer, address = strategy_account.owner)]
+    #[account(signer, mut, address = strategy_account.owner)]
     pub owner: AccountInfo<'info>,
 
     #[account(
         mut,
+        close = owner,
         seeds=[strategy_id.key().as_ref(), mango_strategy::STRATEGY_ACCOUNT_PDA_SEED],
         bump=bumps.strategy_account_bump,
     )]
     pub strategy_account: Box<Account<'info, StrategyAccount>>,
 
+    #[account(
+        mut,
+        seeds=[mango_strategy::REGISTRY_PDA_SEED, &(strategy_account.registry_index / RegistryPage::CAPACITY).to_le_bytes()],
+        bump,
+    )]
+    pub registry_page: Box<Account<'info, RegistryPage>>,
+
     #[account(
         mut,
         close = owner,
-        constraint = strategy_account.limits_account == Some(limits_account.key()))
-    ]
-    pub limits_account: Box<Account<'info, LimitsAccount>>,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::HISTORY_PDA_SEED],
+        bump,
+    )]
+    pub share_price_history: Box<Account<'info, SharePriceHistory>>,
+
+    #[account(
+        mut,
+        close = owner,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::NAV_PDA_SEED],
+        bump,
+    )]
+    pub nav_account: AccountLoader<'info, NavAccount>,
+
+    /// CHECK: closed if batched deposits were used, pending deposits have to be processed or cancelled
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::DEPOSIT_QUEUE_PDA_SEED],
+        bump,
+    )]
+    pub deposit_queue: AccountInfo<'info>,
+
+    /// CHECK: closed if it exists and is empty
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_VAULT_PDA_SEED],
+        bump,
+    )]
+    pub pending_vault_token_account: AccountInfo<'info>,
+
+    /// CHECK: closed if a change is pending
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::PENDING_CHANGE_PDA_SEED],
+        bump,
+    )]
+    pub pending_change: AccountInfo<'info>,
+
+    /// CHECK: system account, lamports left for advanced orders go to the owner
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::TRIGGER_DELEGATE_PDA_SEED],
+        bump,
+    )]
+    pub trigger_delegate: AccountInfo<'info>,
+
+    /// Vault
+    #[account(
+        mut,
+        seeds=[strategy_id.key().as_ref(), mango_strategy::VAULT_PDA_SEED],
+        bump
+    )]
+    pub vault_token_account: Box<Account<'info, TokenAccount>>,
+
+    /// Strategy token
+    #[account(
+        seeds=[strategy_id.key().as_ref(), mango_strategy::MINT_PDA_SEED],
+        bump,
+    )]
+    pub strategy_token_mint: Box<Account<'info, Mint>>,
+
+    pub token_program: Program<'info, Token>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Default)]
@@ -426,9 +2025,9 @@ pub struct Bumps {
 }
 
 #[account]
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct StrategyAccount {
-    /// Owner can only change limits
+    /// Owner can only change limits and fees
     pub owner: Pubkey,
     pub trigger_server_pk: Pubkey,
     pub vault_token_mint: Pubkey,
@@ -436,12 +2035,458 @@ pub struct StrategyAccount {
     pub mango_group: Pubkey,
     pub limits_account: Option<Pubkey>,
     pub market_info: MarketInfo,
+    /// Kept in the vault, raising the share price
+    pub deposit_fee_bps: u16,
+    pub withdraw_fee_bps: u16,
+    /// Seconds since the last deposit before withdraw is allowed
+    pub min_holding_period: i64,
+    pub outflow_limit: OutflowLimit,
+    /// In base lots including resting orders, 0 - no limit
+    pub max_perp_position: u64,
+    pub stats: StrategyStats,
+    /// spot * ratio should match the perp short, 0 - delta not checked
+    pub target_hedge_ratio_bps: u16,
+    /// allowed deviation from the target hedge, relative to spot
+    pub delta_tolerance_bps: u16,
+    pub mode: BasisMode,
+    /// annualised bps, below it perp orders may only reduce the position
+    pub min_funding_rate_bps: Option<i32>,
+    /// perp orders fail if the book mid deviates more from the oracle price, 0 - no limit
+    pub max_book_deviation_bps: u16,
+    /// spot value / tvl, 0 - 1x
+    pub max_leverage_bps: u32,
+    pub keeper_config: KeeperConfig,
+    /// Mango accounts with account_num 1..=mango_account_count
+    pub mango_account_count: u64,
+    /// Seconds between propose_change and execute_change, 0 - setters apply immediately
+    pub timelock_delay: i64,
+    /// Position in the registry, page registry_index / RegistryPage::CAPACITY
+    pub registry_index: u64,
+    /// Min seconds between process_deposits, 0 - direct deposits, otherwise deposits are queued
+    pub deposit_epoch: i64,
+    /// Last time strategy tokens were minted for a deposit
+    pub last_deposit_ts: i64,
+    /// Charged on shares received by transfer, prorated over min_holding_period after
+    /// the withdrawer's last deposit (DepositReceipt), in full without one
+    pub early_exit_fee_bps: u16,
+    /// Set by trip_circuit_breaker, blocks perp orders for CIRCUIT_BREAKER_COOLDOWN
+    /// or until reset_circuit_breaker. 0 - not tripped
+    pub circuit_breaker_tripped_at: i64,
+    /// Last deleverage call, for the keeper cooldown
+    pub last_deleverage_ts: i64,
+    /// Last rebalance_public call, for the keeper cooldown
+    pub last_rebalance_ts: i64,
 }
 
 impl StrategyAccount {
+    pub const LEN: usize = 6 * 32
+        + 12
+        + 2 * 2
+        + 8
+        + OutflowLimit::LEN
+        + 8
+        + StrategyStats::LEN
+        + 2 * 2
+        + 1
+        + (1 + 4)
+        + 2
+        + 4
+        + KeeperConfig::LEN
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 8
+        + 2
+        + 8
+        + 8
+        + 8;
+}
+
+/// StrategyAccount layout before migrate_strategy_account, without the discriminator
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct LegacyStrategyAccount {
+    pub owner: Pubkey,
+    pub trigger_server_pk: Pubkey,
+    pub vault_token_mint: Pubkey,
+    pub mango_program: Pubkey,
+    pub mango_group: Pubkey,
+    pub limits_account: Option<Pubkey>,
+    pub market_info: MarketInfo,
+}
+
+impl LegacyStrategyAccount {
     pub const LEN: usize = 6 * 32 + 12 + 8;
 }
 
+/// Permissionless keeper instructions
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct KeeperConfig {
+    /// maint health ratio below which deleverage is allowed, 0 - disabled
+    pub deleverage_health_ratio_bps: u16,
+    /// hedge drift from the target relative to spot above which rebalance_public is allowed, 0 - disabled
+    pub rebalance_drift_bps: u16,
+    /// max deviation of keeper order prices from the oracle price
+    pub max_slippage_bps: u16,
+    /// native USDC, paid to the caller in strategy tokens
+    pub bounty: u64,
+    /// deleverage pays the bounty only if the maint health ratio improved by at least this
+    pub min_health_improvement_bps: u16,
+    /// min seconds between calls of deleverage, and of rebalance_public
+    pub cooldown: i64,
+    /// rebalance_public pays the bounty only if the drift decreased by at least this
+    pub min_drift_improvement_bps: u16,
+}
+
+impl KeeperConfig {
+    pub const LEN: usize = 2 + 2 + 2 + 8 + 2 + 8 + 2;
+}
+
+/// Cumulative perp stats, native quote
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct StrategyStats {
+    /// > 0: received
+    pub funding_earned: i64,
+    /// Taker fees of rebalance trades, estimated from the traded quote (Mango keeps no per-account fees)
+    pub estimated_fees: u64,
+    /// Rebalance trades against the oracle price, fees included, < 0: worse than the oracle
+    pub slippage: i64,
+    /// I80F48 bits of PerpMarketCache funding at the last update
+    pub long_funding_index: i128,
+    pub short_funding_index: i128,
+    /// base lots at the last update
+    pub base_position: i64,
+}
+
+impl StrategyStats {
+    pub const LEN: usize = 3 * 8 + 2 * 16 + 8;
+}
+
+/// Withdrawals in any `window` seconds are limited to `max_outflow_bps` of the TVL,
+/// tracked in BUCKETS buckets of window / (BUCKETS - 1) seconds
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct OutflowLimit {
+    /// 0 - no limit
+    pub max_outflow_bps: u16,
+    /// in seconds
+    pub window: i64,
+    /// timestamp / bucket length of the last withdrawal
+    pub last_bucket: i64,
+    /// native vault tokens withdrawn per bucket, indexed by bucket % BUCKETS
+    pub outflow: [u64; 8],
+}
+
+impl OutflowLimit {
+    pub const BUCKETS: usize = 8;
+    pub const LEN: usize = 2 + 8 + 8 + Self::BUCKETS * 8;
+
+    /// Adds the withdrawal to the current bucket. The buckets cover at least the last `window`
+    /// seconds, so outflow in any window can't exceed `max_outflow`
+    pub fn record_outflow(&mut self, now: i64, amount: u64, max_outflow: u64) -> Result<()> {
+        let buckets = Self::BUCKETS as i64;
+        let bucket_len = (self.window + buckets - 2) / (buckets - 1);
+        let bucket = now / bucket_len;
+        let expired = (bucket - self.last_bucket).clamp(0, buckets);
+        for i in 0..expired {
+            self.outflow[(bucket - i).rem_euclid(buckets) as usize] = 0;
+        }
+        self.last_bucket = self.last_bucket.max(bucket);
+        let outflow = self
+            .outflow
+            .iter()
+            .map(|outflow| *outflow as u128)
+            .sum::<u128>()
+            + amount as u128;
+        if outflow > max_outflow as u128 {
+            return Err(ErrorCode::OutflowLimitReached.into());
+        }
+        self.outflow[bucket.rem_euclid(buckets) as usize] += amount;
+        Ok(())
+    }
+}
+
+/// Per user, seeds: [strategy_id, "receipt", owner]
+#[account]
+#[derive(Debug, Default)]
+pub struct DepositReceipt {
+    pub last_deposit_ts: i64,
+}
+
+impl DepositReceipt {
+    pub const LEN: usize = 8 + 8;
+}
+
+/// Strategy token price for other programs, seeds: [strategy_id, "nav"].
+/// Refreshed by state-changing instructions and the snapshot crank, read it with the nav-reader crate
+#[account(zero_copy)]
+pub struct NavAccount {
+    pub strategy_account: Pubkey,
+    /// native vault tokens per 10^STRATEGY_TOKEN_DECIMALS strategy tokens
+    pub share_price: u64,
+    /// native vault tokens
+    pub tvl: u64,
+    pub supply: u64,
+    pub last_update_slot: u64,
+    pub last_update_ts: i64,
+    /// unhedged spot value per strategy token in share_price units,
+    /// i.e. the share price change on a 100% spot price move. 0 - fully hedged
+    pub confidence: u64,
+}
+
+impl NavAccount {
+    pub const LEN: usize = 8 + 32 + 6 * 8;
+}
+
+/// Per-user ledger, seeds: [strategy_id, "position", owner].
+/// Only shares minted and burned by the program are tracked, not token transfers
+#[account]
+#[derive(Debug, Default)]
+pub struct UserPosition {
+    pub owner: Pubkey,
+    /// USDC including deposit fees
+    pub total_deposited: u64,
+    /// USDC received after withdraw fees
+    pub total_withdrawn: u64,
+    pub shares: u64,
+    /// USDC paid for the tracked shares
+    pub cost_basis: u64,
+    /// I80F48 bits, cost_basis / shares
+    pub average_entry_price: i128,
+    pub realised_pnl: i64,
+}
+
+impl UserPosition {
+    pub const LEN: usize = 8 + 32 + 8 + 8 + 8 + 8 + 16 + 8;
+
+    /// `vault_token_amount` is the USDC paid including the deposit fee, for direct and queued deposits
+    pub fn record_deposit(&mut self, vault_token_amount: u64, shares: u64) -> Result<()> {
+        self.total_deposited = self
+            .total_deposited
+            .checked_add(vault_token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cost_basis = self
+            .cost_basis
+            .checked_add(vault_token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.shares = self
+            .shares
+            .checked_add(shares)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.update_average_entry_price();
+        Ok(())
+    }
+
+    /// Shares of a withdrawal covered by the position, shares beyond it
+    /// (received by transfer) are withdrawn first
+    pub fn tracked_shares(&self, balance: u64, shares: u64) -> u64 {
+        shares.saturating_sub(balance.saturating_sub(self.shares))
+    }
+
+    /// Proceeds of untracked shares are not realised PnL
+    pub fn record_withdraw(
+        &mut self,
+        vault_token_amount: u64,
+        shares: u64,
+        tracked: u64,
+    ) -> Result<()> {
+        self.total_withdrawn = self
+            .total_withdrawn
+            .checked_add(vault_token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        if tracked == 0 {
+            return Ok(());
+        }
+        let cost = (self.cost_basis as u128 * tracked as u128)
+            .checked_div(self.shares as u128)
+            .and_then(|cost| u64::try_from(cost).ok())
+            .ok_or(ErrorCode::MathOverflow)?;
+        let proceeds = (vault_token_amount as u128 * tracked as u128)
+            .checked_div(shares as u128)
+            .and_then(|proceeds| u64::try_from(proceeds).ok())
+            .ok_or(ErrorCode::MathOverflow)?;
+        let pnl =
+            i64::try_from(proceeds as i128 - cost as i128).map_err(|_| ErrorCode::MathOverflow)?;
+        self.realised_pnl = self
+            .realised_pnl
+            .checked_add(pnl)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.cost_basis = self
+            .cost_basis
+            .checked_sub(cost)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.shares = self
+            .shares
+            .checked_sub(tracked)
+            .ok_or(ErrorCode::MathOverflow)?;
+        self.update_average_entry_price();
+        Ok(())
+    }
+
+    fn update_average_entry_price(&mut self) {
+        self.average_entry_price = if self.shares == 0 {
+            0
+        } else {
+            (I80F48::from_num(self.cost_basis) / I80F48::from_num(self.shares)).to_bits()
+        };
+    }
+}
+
+/// Batched deposits state, seeds: [strategy_id, "deposit_queue"]
+#[account]
+#[derive(Debug, Default)]
+pub struct DepositQueue {
+    /// number of process_deposits calls
+    pub epoch: u64,
+    pub last_processed_at: i64,
+    /// USDC in the pending vault
+    pub pending_amount: u64,
+}
+
+impl DepositQueue {
+    pub const LEN: usize = 8 + 8 + 8 + 8;
+}
+
+/// seeds: [strategy_id, "pending_deposit", owner]
+#[account]
+#[derive(Debug, Default)]
+pub struct PendingDeposit {
+    pub owner: Pubkey,
+    /// USDC including the deposit fee, 0 - nothing queued
+    pub amount: u64,
+    /// deposit queue epoch of the last queue_deposit
+    pub epoch: u64,
+}
+
+impl PendingDeposit {
+    pub const LEN: usize = 8 + 32 + 8 + 8;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, Default)]
+pub struct SharePriceSnapshot {
+    pub timestamp: i64,
+    /// I80F48 bits, vault tokens per strategy token
+    pub share_price: i128,
+    pub tvl: u64,
+}
+
+impl SharePriceSnapshot {
+    pub const LEN: usize = 8 + 16 + 8;
+}
+
+/// Number of strategies initialized by the program, seeds: ["registry"]
+#[account]
+#[derive(Debug, Default)]
+pub struct Registry {
+    pub strategy_count: u64,
+}
+
+impl Registry {
+    pub const LEN: usize = 8 + 8;
+}
+
+/// Strategies registry_index in [page * CAPACITY, (page + 1) * CAPACITY),
+/// seeds: ["registry", page as u64 le bytes]
+#[account]
+#[derive(Debug, Default)]
+pub struct RegistryPage {
+    pub entries: Vec<RegistryEntry>,
+}
+
+impl RegistryPage {
+    pub const CAPACITY: u64 = 64;
+    pub const LEN: usize = 8 + 4 + Self::CAPACITY as usize * RegistryEntry::LEN;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct RegistryEntry {
+    pub strategy_id: Pubkey,
+    pub owner: Pubkey,
+    pub mango_group: Pubkey,
+    pub market_info: MarketInfo,
+    pub created_at: i64,
+    pub deprecated: bool,
+    /// Set by close_strategy
+    pub closed: bool,
+}
+
+impl RegistryEntry {
+    pub const LEN: usize = 3 * 32 + 11 + 8 + 1 + 1;
+}
+
+/// Ring buffer of share price snapshots, seeds: [strategy_id, "history"]
+#[account]
+#[derive(Debug, Default)]
+pub struct SharePriceHistory {
+    /// index of the next write
+    pub next: u16,
+    pub snapshots: Vec<SharePriceSnapshot>,
+}
+
+impl SharePriceHistory {
+    pub const CAPACITY: usize = 128;
+    /// min time between snapshots, CAPACITY * INTERVAL covers 32 days
+    pub const INTERVAL: i64 = 6 * 60 * 60;
+    pub const LEN: usize = 8 + 2 + 4 + Self::CAPACITY * SharePriceSnapshot::LEN;
+
+    const DAY: i64 = 24 * 60 * 60;
+    const YEAR: i64 = 365 * Self::DAY;
+
+    pub fn last(&self) -> Option<&SharePriceSnapshot> {
+        let len = self.snapshots.len();
+        if len == 0 {
+            return None;
+        }
+        self.snapshots.get((self.next as usize + len - 1) % len)
+    }
+
+    /// Oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &SharePriceSnapshot> {
+        let (newer, older) = self.snapshots.split_at(self.next as usize);
+        older.iter().chain(newer.iter())
+    }
+
+    /// Appends unless the last snapshot is less than INTERVAL old, returns true if appended
+    pub fn push(&mut self, snapshot: SharePriceSnapshot) -> bool {
+        if let Some(last) = self.last() {
+            if snapshot.timestamp < last.timestamp + Self::INTERVAL {
+                return false;
+            }
+        }
+        if self.snapshots.len() < Self::CAPACITY {
+            self.snapshots.push(snapshot);
+        } else {
+            self.snapshots[self.next as usize] = snapshot;
+        }
+        self.next = ((self.next as usize + 1) % Self::CAPACITY) as u16;
+        true
+    }
+
+    /// Share price return from the last snapshot at least `period` older than the newest one,
+    /// annualised without compounding. None if the history does not cover `period`.
+    pub fn trailing_apy(&self, period: i64) -> Option<I80F48> {
+        let end = self.last()?;
+        let start = self
+            .iter()
+            .take_while(|s| s.timestamp <= end.timestamp - period)
+            .last()?;
+        let price_change = I80F48::from_bits(end.share_price)
+            .checked_div(I80F48::from_bits(start.share_price))?
+            - I80F48::ONE;
+        price_change
+            .checked_mul(I80F48::from_num(Self::YEAR))?
+            .checked_div(I80F48::from_num(end.timestamp - start.timestamp))
+    }
+
+    pub fn apy_7d(&self) -> Option<I80F48> {
+        self.trailing_apy(7 * Self::DAY)
+    }
+
+    pub fn apy_30d(&self) -> Option<I80F48> {
+        self.trailing_apy(30 * Self::DAY)
+    }
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
 pub struct WhitelistLimit {
     pub key: Pubkey,
@@ -461,3 +2506,52 @@ impl LimitsAccount {
     pub const WHITELIST_CAP: usize = 16;
     pub const LEN: usize = 17 + LimitsAccount::WHITELIST_CAP * 32 + 8;
 }
+
+/// Owner parameter change queued by propose_change
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub enum ParameterChange {
+    Limits {
+        max_tvl: Option<u64>,
+        max_deposit: u64,
+        whitelist: Vec<WhitelistLimit>,
+    },
+    DropLimits,
+    Fees {
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    },
+    MinHoldingPeriod {
+        min_holding_period: i64,
+        early_exit_fee_bps: u16,
+    },
+    OutflowLimit {
+        max_outflow_bps: u16,
+        window: i64,
+    },
+    MaxPerpPosition(u64),
+    HedgeConfig {
+        target_hedge_ratio_bps: u16,
+        delta_tolerance_bps: u16,
+    },
+    Mode(BasisMode),
+    MinFundingRate(Option<i32>),
+    MaxBookDeviation(u16),
+    MaxLeverage(u32),
+    KeeperConfig(KeeperConfig),
+    TimelockDelay(i64),
+    DepositEpoch(i64),
+}
+
+#[account]
+#[derive(Debug)]
+pub struct PendingChange {
+    pub change: ParameterChange,
+    pub proposed_at: i64,
+    /// proposed_at + timelock_delay at the time of the proposal
+    pub executable_at: i64,
+}
+
+impl PendingChange {
+    /// Limits is the largest variant
+    pub const LEN: usize = 1 + LimitsAccount::LEN + 8 + 8;
+}
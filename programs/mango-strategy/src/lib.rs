@@ -1,32 +1,51 @@
 use anchor_lang::prelude::*;
+use anchor_lang::{AccountsClose, AccountsExit, Discriminator};
 use mango::error::MangoError;
 pub mod accounts_types;
 pub mod mango_util;
 use crate::accounts_types::*;
-use crate::mango_util::calculate_token_price;
-use anchor_spl::token::Transfer;
+use crate::mango_util::{
+    calculate_fee, calculate_shares_for_deposit, calculate_vault_tokens_for_withdraw, PerpSnapshot,
+    BPS_DENOMINATOR, USDC_TOKEN_INDEX, VIRTUAL_ASSETS, VIRTUAL_SHARES,
+};
+use anchor_spl::token::{mint_to, Mint, MintTo, TokenAccount, Transfer};
 use fixed::types::I80F48;
 pub use mango;
+use mango::state::{AdvancedOrders, HealthType, MangoAccount, ADVANCED_ORDER_FEE};
 pub use mango_common;
 
 declare_id!("DNyxh1hUP2TmLr6mh4yHEyWNPNiZsiUh6vY3snRi9M8F");
 
 #[program]
 pub mod mango_strategy {
-    use anchor_spl::token::{burn, mint_to, Burn, MintTo};
-    use az::{Cast, CheckedCast};
+    use anchor_spl::token::{burn, close_account, mint_to, Burn, CloseAccount, MintTo};
     use solana_program::entrypoint::ProgramResult;
 
-    use crate::mango_util::calculate_tvl;
-
     use super::*;
 
     pub const STRATEGY_ACCOUNT_PDA_SEED: &[u8] = b"account";
     pub const VAULT_PDA_SEED: &[u8] = b"vault";
     pub const MINT_PDA_SEED: &[u8] = b"mint";
+    pub const DEPOSIT_RECEIPT_PDA_SEED: &[u8] = b"receipt";
+    pub const TRIGGER_DELEGATE_PDA_SEED: &[u8] = b"delegate";
+    pub const HISTORY_PDA_SEED: &[u8] = b"history";
+    pub const PENDING_CHANGE_PDA_SEED: &[u8] = b"pending";
+    pub const REGISTRY_PDA_SEED: &[u8] = b"registry";
+    pub const DEPOSIT_QUEUE_PDA_SEED: &[u8] = b"deposit_queue";
+    pub const PENDING_VAULT_PDA_SEED: &[u8] = b"pending_vault";
+    pub const PENDING_DEPOSIT_PDA_SEED: &[u8] = b"pending_deposit";
+    pub const USER_POSITION_PDA_SEED: &[u8] = b"position";
+    pub const NAV_PDA_SEED: &[u8] = b"nav";
 
-    pub const MANGO_ACCOUNT_NUM: u64 = 1;
+    pub const MANGO_ACCOUNT_NUM: u64 = 1; // main account, deposits and withdrawals
+    pub const MAX_MANGO_ACCOUNTS: u64 = 4;
     pub const STRATEGY_TOKEN_DECIMALS: u8 = 6; // same as USDC
+    pub const MAX_FEE_BPS: u16 = 500; // 5%
+    pub const MAX_LEVERAGE_BPS: u32 = 30_000; // 3x
+    pub const MAX_KEEPER_BOUNTY: u64 = 10_000000; // 10 USDC
+    pub const DELEVERAGE_BPS: u64 = 2_500; // 25% of the position per call
+    pub const MAX_TIMELOCK_DELAY: i64 = 30 * 24 * 3600; // 30 days
+    pub const CIRCUIT_BREAKER_COOLDOWN: i64 = 3600; // 1 hour
 
     pub fn initialize(
         ctx: Context<Initialize>,
@@ -41,6 +60,22 @@ pub mod mango_strategy {
         ctx.accounts.strategy_account.mango_group = ctx.accounts.mango_group.key();
         ctx.accounts.strategy_account.limits_account = limits_account;
         ctx.accounts.strategy_account.market_info = market_info;
+        ctx.accounts.strategy_account.mango_account_count = MANGO_ACCOUNT_NUM;
+        ctx.accounts.nav_account.load_init()?.strategy_account =
+            ctx.accounts.strategy_account.key();
+
+        let registry_index = ctx.accounts.registry.strategy_count;
+        ctx.accounts.strategy_account.registry_index = registry_index;
+        ctx.accounts.registry.strategy_count = registry_index + 1;
+        ctx.accounts.registry_page.entries.push(RegistryEntry {
+            strategy_id: ctx.accounts.strategy_id.key(),
+            owner: ctx.accounts.deployer.key(),
+            mango_group: ctx.accounts.mango_group.key(),
+            market_info: ctx.accounts.strategy_account.market_info.clone(),
+            created_at: Clock::get()?.unix_timestamp,
+            deprecated: false,
+            closed: false,
+        });
 
         let strategy_id = ctx.accounts.strategy_id.key();
         mango_util::create_account(
@@ -78,51 +113,48 @@ pub mod mango_strategy {
         Ok(())
     }
 
-    pub fn deposit(ctx: Context<Deposit>, bumps: Bumps, vault_token_amount: u64) -> Result<()> {
-        let tvl = calculate_tvl(
+    pub fn deposit(
+        ctx: Context<Deposit>,
+        bumps: Bumps,
+        vault_token_amount: u64,
+        min_shares_out: u64,
+    ) -> Result<()> {
+        if ctx.accounts.strategy_account.deposit_epoch > 0 {
+            return Err(ErrorCode::BatchedDepositsOnly.into());
+        }
+        let tvl = calculate_strategy_tvl(
+            &ctx.accounts.strategy_account,
             &ctx.accounts.mango_program,
             &ctx.accounts.mango_group,
             &ctx.accounts.mango_account,
             &ctx.accounts.mango_cache,
-            &ctx.accounts.strategy_account.market_info,
-        )
-        .map_err(ErrorCode::register_mango_error)?;
-        if let Some(limits_account) = ctx.accounts.strategy_account.limits_account {
-            let limits_account_info = ctx
-                .remaining_accounts
-                .iter()
-                .find(|acc| acc.key() == limits_account)
-                .ok_or(ErrorCode::InvalidLimitsAccount)?; // check limits account
-            let mut limits_account: LimitsAccount =
-                LimitsAccount::try_deserialize(&mut &limits_account_info.data.borrow_mut()[..])
-                    .map_err(|_| ErrorCode::InvalidLimitsAccount)?;
-            if limits_account
-                .max_tvl
-                .map(|max_tvl_limit| (tvl + I80F48::from_num(vault_token_amount)) >= max_tvl_limit)
-                == Some(true)
-            {
-                return Err(ErrorCode::TvlLimitReached.into());
-            }
-            let limit = limits_account
-                .whitelist
-                .iter_mut()
-                .find(|x| x.key == ctx.accounts.owner.key());
-            if let Some(WhitelistLimit { deposit, .. }) = limit {
-                *deposit += vault_token_amount;
-                if *deposit > limits_account.max_deposit {
-                    return Err(ErrorCode::WhitelistLimitReached.into());
-                }
-                LimitsAccount::try_serialize(
-                    &limits_account,
-                    &mut &mut limits_account_info.data.borrow_mut()[..],
-                )?;
-            } else {
-                return Err(ErrorCode::NotInWhitelist.into());
-            }
+            ctx.remaining_accounts,
+        )?;
+        check_deposit_limits(
+            &ctx.accounts.strategy_account,
+            ctx.remaining_accounts,
+            &ctx.accounts.owner.key(),
+            tvl,
+            vault_token_amount,
+        )?;
+        // fee is deposited together with the rest, but no shares are minted for it
+        let fee = calculate_fee(
+            vault_token_amount,
+            ctx.accounts.strategy_account.deposit_fee_bps,
+        );
+        let strategy_token_amount = calculate_shares_for_deposit(
+            ctx.accounts.strategy_token_mint.supply,
+            tvl,
+            vault_token_amount - fee,
+        )?;
+        if strategy_token_amount == 0 || strategy_token_amount < min_shares_out {
+            return Err(ErrorCode::SlippageExceeded.into());
         }
-        let token_price = calculate_token_price(&ctx.accounts.strategy_token_mint, tvl)
-            .map_err(ErrorCode::register_mango_error)?;
-        let strategy_token_amount = I80F48::from_num(vault_token_amount) / token_price;
+        record_share_price(
+            &mut ctx.accounts.share_price_history,
+            &ctx.accounts.strategy_token_mint,
+            tvl,
+        )?;
         let accounts = Transfer {
             authority: ctx.accounts.owner.clone(),
             from: ctx.accounts.deposit_token_account.to_account_info(),
@@ -167,12 +199,31 @@ pub mod mango_strategy {
             cpi_accounts,
             seeds,
         );
-        mint_to(
-            cpi_context,
-            strategy_token_amount
-                .checked_cast()
-                .expect("strategy_token_amount cast failed"),
+        mint_to(cpi_context, strategy_token_amount)?;
+        let now = Clock::get()?.unix_timestamp;
+        ctx.accounts.deposit_receipt.last_deposit_ts = now;
+        ctx.accounts.strategy_account.last_deposit_ts = now;
+        ctx.accounts.user_position.owner = ctx.accounts.owner.key();
+        ctx.accounts
+            .user_position
+            .record_deposit(vault_token_amount, strategy_token_amount)?;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
         )?;
+        emit!(DepositEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            owner: ctx.accounts.owner.key(),
+            vault_token_amount,
+            strategy_token_amount,
+            fee,
+        });
         Ok(())
     }
 
@@ -180,18 +231,63 @@ pub mod mango_strategy {
         ctx: Context<Withdraw>,
         bumps: Bumps,
         strategy_token_amount: u64,
+        min_vault_tokens_out: u64,
     ) -> Result<()> {
-        let tvl = calculate_tvl(
+        let now = Clock::get()?.unix_timestamp;
+        let holding_period_end = ctx.accounts.deposit_receipt.last_deposit_ts
+            + ctx.accounts.strategy_account.min_holding_period;
+        if now < holding_period_end {
+            return Err(ErrorCode::HoldingPeriodNotElapsed.into());
+        }
+        let tvl = calculate_strategy_tvl(
+            &ctx.accounts.strategy_account,
             &ctx.accounts.mango_program,
             &ctx.accounts.mango_group,
             &ctx.accounts.mango_account,
             &ctx.accounts.mango_cache,
-            &ctx.accounts.strategy_account.market_info,
-        )
-        .map_err(ErrorCode::register_mango_error)?;
-        let token_price = calculate_token_price(&ctx.accounts.strategy_token_mint, tvl)
-            .map_err(ErrorCode::register_mango_error)?;
-        let vault_token_amount = I80F48::from_num(strategy_token_amount) * token_price;
+            ctx.remaining_accounts,
+        )?;
+        let vault_token_amount = calculate_vault_tokens_for_withdraw(
+            ctx.accounts.strategy_token_mint.supply,
+            tvl,
+            strategy_token_amount,
+        )?;
+        // shares received by transfer are not covered by the receipt, they pay the early exit fee
+        let tracked_shares = ctx.accounts.user_position.tracked_shares(
+            ctx.accounts.strategy_token_account.amount,
+            strategy_token_amount,
+        );
+        let untracked_value = (vault_token_amount as u128
+            * (strategy_token_amount - tracked_shares) as u128
+            / strategy_token_amount.max(1) as u128) as u64;
+        // fees are left in the mango account
+        let withdraw_fee = calculate_fee(
+            vault_token_amount,
+            ctx.accounts.strategy_account.withdraw_fee_bps,
+        );
+        let early_exit_fee = calculate_early_exit_fee(
+            &ctx.accounts.strategy_account,
+            ctx.accounts.deposit_receipt.last_deposit_ts,
+            now,
+            untracked_value,
+        );
+        let fee = withdraw_fee + early_exit_fee;
+        let vault_token_amount = vault_token_amount.saturating_sub(fee);
+        record_share_price(
+            &mut ctx.accounts.share_price_history,
+            &ctx.accounts.strategy_token_mint,
+            tvl,
+        )?;
+        let outflow_limit = &mut ctx.accounts.strategy_account.outflow_limit;
+        if outflow_limit.max_outflow_bps > 0 {
+            let tvl: u64 = tvl.checked_to_num().ok_or(ErrorCode::InvalidTvl)?;
+            let max_outflow = (tvl as u128 * outflow_limit.max_outflow_bps as u128
+                / BPS_DENOMINATOR as u128) as u64;
+            outflow_limit.record_outflow(now, vault_token_amount, max_outflow)?;
+        }
+        if vault_token_amount < min_vault_tokens_out {
+            return Err(ErrorCode::SlippageExceeded.into());
+        }
         if let Some(limits_account) = ctx.accounts.strategy_account.limits_account {
             let limits_account_info = ctx
                 .remaining_accounts
@@ -206,12 +302,7 @@ pub mod mango_strategy {
                 .iter_mut()
                 .find(|x| x.key == ctx.accounts.owner.key());
             if let Some(WhitelistLimit { deposit, .. }) = limit {
-                if vault_token_amount >= *deposit {
-                    *deposit = 0;
-                } else {
-                    let vault_token_amount_u64: u64 = vault_token_amount.cast();
-                    *deposit -= vault_token_amount_u64;
-                }
+                *deposit = deposit.saturating_sub(vault_token_amount);
                 LimitsAccount::try_serialize(
                     &limits_account,
                     &mut &mut limits_account_info.data.borrow_mut()[..],
@@ -238,9 +329,7 @@ pub mod mango_strategy {
                 STRATEGY_ACCOUNT_PDA_SEED,
                 &[bumps.strategy_account_bump],
             ]],
-            vault_token_amount
-                .checked_cast()
-                .expect("vault_token_amount cast failed"),
+            vault_token_amount,
             ctx.accounts.strategy_account.market_info.spot_market_index as usize,
         )?;
         let cpi_accounts = Burn {
@@ -251,6 +340,29 @@ pub mod mango_strategy {
         let cpi_context =
             CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
         burn(cpi_context, strategy_token_amount)?;
+        ctx.accounts.user_position.owner = ctx.accounts.owner.key();
+        ctx.accounts.user_position.record_withdraw(
+            vault_token_amount,
+            strategy_token_amount,
+            tracked_shares,
+        )?;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        emit!(WithdrawEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            owner: ctx.accounts.owner.key(),
+            vault_token_amount,
+            strategy_token_amount,
+            fee,
+        });
         Ok(())
     }
 
@@ -260,13 +372,60 @@ pub mod mango_strategy {
         bumps: Bumps,
         amount: i64,
         reduce_only: bool,
-    ) -> ProgramResult {
+    ) -> Result<()> {
         assert_ne!(amount, 0, "Amount should not be zero");
         let side = if amount > 0 {
             mango::matching::Side::Bid
         } else {
             mango::matching::Side::Ask
         };
+        check_circuit_breaker(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+        )?;
+        let reduce_only = check_funding_rate(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+            side,
+        )? || reduce_only;
+        if !reduce_only {
+            check_perp_position_limit(
+                &ctx.accounts.strategy_account,
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                side,
+                amount.abs(),
+            )?;
+        }
+        let positions_before = get_position_check(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.spot_open_orders,
+        )?;
+        let market_index = ctx.accounts.strategy_account.market_info.perp_market_index as usize;
+        let before = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            market_index,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
         mango_util::adjust_position_perp(
             &ctx.accounts.mango_program,
             &ctx.accounts.mango_group,
@@ -285,105 +444,3078 @@ pub mod mango_strategy {
             ]],
             side,
             amount.abs(),
-            ctx.accounts.strategy_account.market_info.perp_market_index as usize,
+            market_index,
             reduce_only,
         )?;
+        let after = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            market_index,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        check_position_change(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.spot_open_orders,
+            positions_before,
+        )?;
+        let (funding, estimated_fee, slippage) = mango_util::record_perp_stats(
+            &mut ctx.accounts.strategy_account.stats,
+            &before,
+            &after,
+        )?;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        emit!(RebalanceEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            base_change: after.base_position - before.base_position,
+            funding,
+            estimated_fee,
+            slippage,
+        });
         Ok(())
     }
 
-    pub fn adjust_position_spot(
-        ctx: Context<AdjustPositionSpot>,
+    /// Resting perp order at `price` (quote lots per base lot),
+    /// amount > 0: bid, amount < 0: ask
+    pub fn place_perp_order(
+        ctx: Context<AdjustPositionPerp>,
         bumps: Bumps,
+        price: i64,
         amount: i64,
-    ) -> ProgramResult {
+        client_order_id: u64,
+        order_type: MakerOrderType,
+        reduce_only: bool,
+    ) -> Result<()> {
         assert_ne!(amount, 0, "Amount should not be zero");
         let side = if amount > 0 {
-            serum_dex::matching::Side::Bid
+            mango::matching::Side::Bid
         } else {
-            serum_dex::matching::Side::Ask
+            mango::matching::Side::Ask
         };
-        mango_util::adjust_position_spot(
+        check_circuit_breaker(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+        )?;
+        let reduce_only = check_funding_rate(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+            side,
+        )? || reduce_only;
+        if !reduce_only {
+            check_perp_position_limit(
+                &ctx.accounts.strategy_account,
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                side,
+                amount.abs(),
+            )?;
+        }
+        let market_index = ctx.accounts.strategy_account.market_info.perp_market_index as usize;
+        let before = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            market_index,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        mango_util::place_perp_order_signed(
             &ctx.accounts.mango_program,
             &ctx.accounts.mango_group,
             &ctx.accounts.mango_account,
             &ctx.accounts.strategy_account.to_account_info(),
             &ctx.accounts.mango_cache,
-            &ctx.accounts.mango_signer,
-            &ctx.accounts.serum_dex,
-            &ctx.accounts.spot_market,
-            &ctx.accounts.spot_bids,
-            &ctx.accounts.spot_asks,
-            &ctx.accounts.spot_request_queue,
-            &ctx.accounts.spot_event_queue,
-            &ctx.accounts.spot_base,
-            &ctx.accounts.spot_quote,
-            &ctx.accounts.spot_base_root_bank,
-            &ctx.accounts.spot_base_node_bank,
-            &ctx.accounts.spot_base_vault,
-            &ctx.accounts.spot_quote_root_bank,
-            &ctx.accounts.spot_quote_node_bank,
-            &ctx.accounts.spot_quote_vault,
-            &ctx.accounts.serum_dex_signer,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+            &ctx.accounts.mango_event_queue,
             &ctx.accounts.spot_open_orders,
-            &ctx.accounts.srm_vault,
-            &ctx.accounts.token_program,
             &[&[
                 ctx.accounts.strategy_id.key().as_ref(),
                 STRATEGY_ACCOUNT_PDA_SEED,
                 &[bumps.strategy_account_bump],
             ]],
             side,
-            amount.abs() as u64,
-            ctx.accounts
-                .strategy_account
-                .market_info
-                .spot_market_lot_size,
+            price,
+            amount.abs(),
+            client_order_id,
+            order_type.into(),
+            market_index,
+            reduce_only,
+        )?;
+        let after = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            market_index,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        mango_util::record_perp_stats(&mut ctx.accounts.strategy_account.stats, &before, &after)?;
+        // placing settles funding
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
         )?;
         Ok(())
     }
 
-    pub fn set_limits(
-        ctx: Context<SetLimits>,
+    /// Batched deposits: USDC waits in the pending vault until the next process_deposits
+    pub fn queue_deposit(
+        ctx: Context<QueueDeposit>,
         bumps: Bumps,
-        max_tvl: Option<u64>,
-        max_deposit: u64,
-        whitelist: Vec<WhitelistLimit>,
-    ) -> ProgramResult {
-        ctx.accounts.limits_account.max_tvl = max_tvl;
-        ctx.accounts.limits_account.max_deposit = max_deposit;
-        ctx.accounts.limits_account.whitelist = whitelist;
-        ctx.accounts.strategy_account.limits_account = Some(ctx.accounts.limits_account.key());
+        vault_token_amount: u64,
+    ) -> Result<()> {
+        if ctx.accounts.strategy_account.deposit_epoch == 0 {
+            return Err(ErrorCode::BatchedDepositsDisabled.into());
+        }
+        let tvl = calculate_strategy_tvl(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        check_deposit_limits(
+            &ctx.accounts.strategy_account,
+            ctx.remaining_accounts,
+            &ctx.accounts.owner.key(),
+            tvl + I80F48::from_num(ctx.accounts.deposit_queue.pending_amount),
+            vault_token_amount,
+        )?;
+        let accounts = Transfer {
+            authority: ctx.accounts.owner.clone(),
+            from: ctx.accounts.deposit_token_account.to_account_info(),
+            to: ctx.accounts.pending_vault_token_account.to_account_info(),
+        };
+        let transfer_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), accounts);
+        anchor_spl::token::transfer(transfer_ctx, vault_token_amount)?;
+
+        let epoch = ctx.accounts.deposit_queue.epoch;
+        let pending_deposit = &mut ctx.accounts.pending_deposit;
+        pending_deposit.owner = ctx.accounts.owner.key();
+        pending_deposit.amount += vault_token_amount;
+        pending_deposit.epoch = epoch;
+        ctx.accounts.user_position.owner = ctx.accounts.owner.key();
+        ctx.accounts.deposit_queue.pending_amount += vault_token_amount;
+        // holding period starts when the deposit is queued
+        ctx.accounts.deposit_receipt.last_deposit_ts = Clock::get()?.unix_timestamp;
+        emit!(DepositQueuedEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            owner: ctx.accounts.owner.key(),
+            vault_token_amount,
+            epoch,
+        });
         let _ = bumps; // bumps used in validation
         Ok(())
     }
 
-    pub fn drop_limits(ctx: Context<DropLimits>, bumps: Bumps) -> ProgramResult {
-        ctx.accounts.strategy_account.limits_account = None;
-        let _ = bumps; // bumps used in validation
+    /// Returns the whole queued amount and releases it from the whitelist limit
+    pub fn cancel_deposit(ctx: Context<CancelDeposit>, bumps: Bumps) -> Result<()> {
+        let vault_token_amount = ctx.accounts.pending_deposit.amount;
+        let accounts = Transfer {
+            authority: ctx.accounts.strategy_account.to_account_info(),
+            from: ctx.accounts.pending_vault_token_account.to_account_info(),
+            to: ctx.accounts.deposit_token_account.to_account_info(),
+        };
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let bump = &[bumps.strategy_account_bump];
+        let seeds = &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]];
+        let transfer_ctx = CpiContext::new_with_signer(
+            ctx.accounts.token_program.to_account_info(),
+            accounts,
+            seeds,
+        );
+        anchor_spl::token::transfer(transfer_ctx, vault_token_amount)?;
+        release_deposit_limit(
+            &ctx.accounts.strategy_account,
+            ctx.remaining_accounts,
+            &ctx.accounts.owner.key(),
+            vault_token_amount,
+        )?;
+        ctx.accounts.pending_deposit.amount = 0;
+        ctx.accounts.deposit_queue.pending_amount = ctx
+            .accounts
+            .deposit_queue
+            .pending_amount
+            .checked_sub(vault_token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        emit!(DepositCancelledEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            owner: ctx.accounts.owner.key(),
+            vault_token_amount,
+        });
         Ok(())
     }
-}
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
-pub struct MarketInfo {
-    pub perp_market_index: u8,
-    pub spot_market_index: u8,
-    pub spot_market_lot_size: u64,
-    pub spot_token_index: u8,
-}
+    /// Permissionless once per deposit epoch. Refreshes the Mango cache, deposits the queued USDC,
+    /// scales the spot and perp positions by deposits / TVL within the keeper slippage bound
+    /// and mints shares for the TVL gained, so all queued deposits get the same share price
+    pub fn process_deposits<'info>(
+        ctx: Context<'_, '_, '_, 'info, ProcessDeposits<'info>>,
+        bumps: Bumps,
+    ) -> Result<()> {
+        let keeper = &ctx.accounts.keeper;
+        let strategy_account = &keeper.strategy_account;
+        if strategy_account.deposit_epoch == 0 {
+            return Err(ErrorCode::BatchedDepositsDisabled.into());
+        }
+        let now = Clock::get()?.unix_timestamp;
+        if now < ctx.accounts.deposit_queue.last_processed_at + strategy_account.deposit_epoch {
+            return Err(ErrorCode::DepositEpochNotElapsed.into());
+        }
+        mango_util::refresh_cache(
+            &keeper.mango_program,
+            &keeper.mango_group,
+            &keeper.mango_cache,
+            &ctx.accounts.oracle,
+            &keeper.mango_market,
+            &[
+                (&keeper.spot_base_root_bank, &keeper.spot_base_node_bank),
+                (&keeper.spot_quote_root_bank, &keeper.spot_quote_node_bank),
+            ],
+        )?;
 
-#[error_code]
-pub enum ErrorCode {
-    InvalidLimitsAccount,
-    TvlLimitReached,
-    WhitelistLimitReached,
-    NotInWhitelist,
-    MangoError,
-}
+        let mango_account_count =
+            (strategy_account.mango_account_count - MANGO_ACCOUNT_NUM) as usize;
+        if ctx.remaining_accounts.len() < mango_account_count
+            || (ctx.remaining_accounts.len() - mango_account_count) % 3 != 0
+        {
+            return Err(ErrorCode::InvalidPendingDeposit.into());
+        }
+        let (mango_accounts, pending_accounts) =
+            ctx.remaining_accounts.split_at(mango_account_count);
+        let mut deposits: Vec<(
+            Account<'info, PendingDeposit>,
+            &AccountInfo<'info>,
+            Account<'info, UserPosition>,
+        )> = Vec::new();
+        let mut vault_token_amount: u64 = 0;
+        for triple in pending_accounts.chunks(3) {
+            let pending_deposit = Account::<PendingDeposit>::try_from(&triple[0])?;
+            let (address, _bump) = Pubkey::find_program_address(
+                &[
+                    keeper.strategy_id.key().as_ref(),
+                    PENDING_DEPOSIT_PDA_SEED,
+                    pending_deposit.owner.as_ref(),
+                ],
+                ctx.program_id,
+            );
+            let strategy_token_account = Account::<TokenAccount>::try_from(&triple[1])?;
+            let user_position = Account::<UserPosition>::try_from(&triple[2])?;
+            let (user_position_address, _bump) = Pubkey::find_program_address(
+                &[
+                    keeper.strategy_id.key().as_ref(),
+                    USER_POSITION_PDA_SEED,
+                    pending_deposit.owner.as_ref(),
+                ],
+                ctx.program_id,
+            );
+            if address != triple[0].key()
+                || deposits.iter().any(|(other, _, _)| other.key() == address)
+                || strategy_token_account.mint != keeper.strategy_token_mint.key()
+                || strategy_token_account.owner != pending_deposit.owner
+                || user_position_address != triple[2].key()
+            {
+                return Err(ErrorCode::InvalidPendingDeposit.into());
+            }
+            vault_token_amount = vault_token_amount
+                .checked_add(pending_deposit.amount)
+                .ok_or(ErrorCode::MathOverflow)?;
+            deposits.push((pending_deposit, &triple[1], user_position));
+        }
+        if vault_token_amount == 0 {
+            return Err(ErrorCode::NoPendingDeposits.into());
+        }
+
+        let tvl_before = calculate_strategy_tvl(
+            strategy_account,
+            &keeper.mango_program,
+            &keeper.mango_group,
+            &keeper.mango_account,
+            &keeper.mango_cache,
+            mango_accounts,
+        )?;
+        let strategy_id = keeper.strategy_id.key();
+        let bump = &[bumps.strategy_account_bump];
+        let seeds = &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]];
+        mango_util::deposit_tokens(
+            &keeper.mango_program,
+            &keeper.mango_group,
+            &keeper.mango_account,
+            &keeper.mango_cache,
+            &keeper.spot_quote_root_bank,
+            &keeper.spot_quote_node_bank,
+            &keeper.spot_quote_vault,
+            &strategy_account.to_account_info(),
+            &keeper.token_program,
+            &ctx.accounts.pending_vault_token_account.to_account_info(),
+            seeds,
+            vault_token_amount,
+        )?;
+
+        let perp_trade = if tvl_before > 0 {
+            let market_info = &strategy_account.market_info;
+            let base_position = mango_util::get_perp_snapshot(
+                &keeper.mango_program,
+                &keeper.mango_group,
+                &keeper.mango_account,
+                &keeper.mango_cache,
+                market_info.perp_market_index as usize,
+            )
+            .map_err(ErrorCode::register_mango_error)?
+            .base_position;
+            let (spot, _perp) = mango_util::get_hedge_positions(
+                &keeper.mango_program,
+                &keeper.mango_group,
+                &keeper.mango_account,
+                &keeper.mango_cache,
+                market_info,
+            )
+            .map_err(ErrorCode::register_mango_error)?;
+            let scale = I80F48::from_num(vault_token_amount) / tvl_before;
+            let perp_quantity: i64 = I80F48::from_num(base_position.abs())
+                .checked_mul(scale)
+                .and_then(|quantity| quantity.checked_floor())
+                .and_then(|quantity| quantity.checked_to_num())
+                .ok_or(ErrorCode::MathOverflow)?;
+            let perp_trade = public_keeper_trade(
+                keeper,
+                &bumps,
+                base_position > 0,
+                perp_quantity,
+                false,
+                spot > 0,
+                spot.abs() * scale,
+            )?;
+            Some(perp_trade)
+        } else {
+            None
+        };
+
+        let tvl_after = calculate_strategy_tvl(
+            strategy_account,
+            &keeper.mango_program,
+            &keeper.mango_group,
+            &keeper.mango_account,
+            &keeper.mango_cache,
+            mango_accounts,
+        )?;
+        // deployment costs are borne by the batch, shares are priced at tvl_before
+        let tvl_gained = (tvl_after - tvl_before).max(I80F48::ZERO);
+        record_share_price(
+            &mut ctx.accounts.share_price_history,
+            &keeper.strategy_token_mint,
+            tvl_before,
+        )?;
+        let mut strategy_token_amount = 0;
+        for (pending_deposit, strategy_token_account, user_position) in deposits.iter_mut() {
+            let fee = calculate_fee(pending_deposit.amount, strategy_account.deposit_fee_bps);
+            let value = tvl_gained * I80F48::from_num(pending_deposit.amount - fee)
+                / I80F48::from_num(vault_token_amount);
+            // mint supply is not reloaded, every deposit is priced against the same supply
+            let shares = calculate_shares_for_deposit(
+                keeper.strategy_token_mint.supply,
+                tvl_before,
+                value.checked_to_num().ok_or(ErrorCode::MathOverflow)?,
+            )?;
+            // the keeper leaves out deposits too small for a share, they stay queued
+            if shares == 0 {
+                return Err(ErrorCode::SlippageExceeded.into());
+            }
+            let cpi_accounts = MintTo {
+                mint: keeper.strategy_token_mint.to_account_info(),
+                to: (*strategy_token_account).clone(),
+                authority: strategy_account.to_account_info(),
+            };
+            let cpi_context = CpiContext::new_with_signer(
+                keeper.token_program.to_account_info(),
+                cpi_accounts,
+                seeds,
+            );
+            mint_to(cpi_context, shares)?;
+            emit!(DepositEvent {
+                strategy_account: strategy_account.key(),
+                owner: pending_deposit.owner,
+                vault_token_amount: pending_deposit.amount,
+                strategy_token_amount: shares,
+                fee,
+            });
+            user_position.record_deposit(pending_deposit.amount, shares)?;
+            user_position.exit(ctx.program_id)?;
+            strategy_token_amount += shares;
+            pending_deposit.amount = 0;
+            pending_deposit.exit(ctx.program_id)?;
+        }
+
+        refresh_nav(
+            &keeper.nav_account,
+            &keeper.strategy_account,
+            &keeper.strategy_token_mint,
+            &keeper.mango_program,
+            &keeper.mango_group,
+            &keeper.mango_account,
+            &keeper.mango_cache,
+            mango_accounts,
+        )?;
+
+        let deposit_queue = &mut ctx.accounts.deposit_queue;
+        deposit_queue.pending_amount = deposit_queue
+            .pending_amount
+            .checked_sub(vault_token_amount)
+            .ok_or(ErrorCode::MathOverflow)?;
+        deposit_queue.last_processed_at = now;
+        deposit_queue.epoch += 1;
+        emit!(DepositsProcessedEvent {
+            strategy_account: strategy_account.key(),
+            epoch: deposit_queue.epoch - 1,
+            vault_token_amount,
+            strategy_token_amount,
+            tvl_before: tvl_before.checked_to_num().unwrap_or(0),
+            tvl_after: tvl_after.checked_to_num().unwrap_or(0),
+        });
+        let strategy_account = &mut ctx.accounts.keeper.strategy_account;
+        if let Some((before, after)) = perp_trade {
+            mango_util::record_perp_stats(&mut strategy_account.stats, &before, &after)?;
+        }
+        strategy_account.last_deposit_ts = now;
+        Ok(())
+    }
+
+    /// Permissionless, refreshes the NAV account and appends a share price snapshot
+    /// if the last one is older than the interval
+    pub fn snapshot(ctx: Context<Snapshot>, bumps: Bumps) -> Result<()> {
+        let tvl = calculate_strategy_tvl(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        if record_share_price(
+            &mut ctx.accounts.share_price_history,
+            &ctx.accounts.strategy_token_mint,
+            tvl,
+        )? {
+            let history = &ctx.accounts.share_price_history;
+            let last = history.last().expect("snapshot recorded");
+            emit!(SharePriceEvent {
+                strategy_account: ctx.accounts.strategy_account.key(),
+                timestamp: last.timestamp,
+                share_price: last.share_price,
+                tvl: last.tvl,
+                apy_7d: history.apy_7d().map(I80F48::to_bits),
+                apy_30d: history.apy_30d().map(I80F48::to_bits),
+            });
+        }
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Permissionless, below the deleverage health ratio reduces the perp and spot positions
+    /// by DELEVERAGE_BPS within the keeper slippage bound,
+    /// pays the bounty if the health ratio improved by the keeper minimum
+    pub fn deleverage(ctx: Context<PublicKeeper>, bumps: Bumps) -> Result<()> {
+        let keeper_config = ctx.accounts.strategy_account.keeper_config.clone();
+        let market_info = ctx.accounts.strategy_account.market_info.clone();
+        let health_ratio = mango_util::get_maint_health_ratio(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &market_info,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        if keeper_config.deleverage_health_ratio_bps == 0
+            || health_ratio >= I80F48::from_num(keeper_config.deleverage_health_ratio_bps)
+        {
+            return Err(ErrorCode::DeleverageNotAllowed.into());
+        }
+        let now = Clock::get()?.unix_timestamp;
+        check_keeper_cooldown(
+            ctx.accounts.strategy_account.last_deleverage_ts,
+            keeper_config.cooldown,
+            now,
+        )?;
+        let base_position = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            market_info.perp_market_index as usize,
+        )
+        .map_err(ErrorCode::register_mango_error)?
+        .base_position;
+        let (spot, _perp) = mango_util::get_hedge_positions(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &market_info,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        let perp_quantity = base_position.abs() * DELEVERAGE_BPS as i64 / BPS_DENOMINATOR as i64;
+        let spot_quantity =
+            spot.abs() * I80F48::from_num(DELEVERAGE_BPS) / I80F48::from_num(BPS_DENOMINATOR);
+        let (before, after) = public_keeper_trade(
+            ctx.accounts,
+            &bumps,
+            base_position < 0,
+            perp_quantity,
+            true,
+            spot < 0,
+            spot_quantity,
+        )?;
+        mango_util::record_perp_stats(&mut ctx.accounts.strategy_account.stats, &before, &after)?;
+        let health_ratio_after = mango_util::get_maint_health_ratio(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &market_info,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        let min_improvement = I80F48::from_num(keeper_config.min_health_improvement_bps);
+        let bounty = if health_ratio_after.saturating_sub(health_ratio) >= min_improvement {
+            pay_keeper_bounty(ctx.accounts, &bumps, ctx.remaining_accounts)?
+        } else {
+            0
+        };
+        ctx.accounts.strategy_account.last_deleverage_ts = now;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        emit!(DeleverageEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            health_ratio_bps: health_ratio.checked_to_num().unwrap_or(i64::MAX),
+            health_ratio_bps_after: health_ratio_after.checked_to_num().unwrap_or(i64::MAX),
+            bounty,
+        });
+        Ok(())
+    }
+
+    /// Permissionless, if the hedge drifted from the target by more than the keeper rebalance drift,
+    /// trades the perp back to the target within the keeper slippage bound,
+    /// pays the bounty if the drift decreased by the keeper minimum
+    pub fn rebalance_public(ctx: Context<PublicKeeper>, bumps: Bumps) -> Result<()> {
+        let strategy_account = &ctx.accounts.strategy_account;
+        let drift_bps_before = get_drift_bps(ctx.accounts)?;
+        if strategy_account.keeper_config.rebalance_drift_bps == 0
+            || strategy_account.target_hedge_ratio_bps == 0
+            || drift_bps_before
+                <= I80F48::from_num(strategy_account.keeper_config.rebalance_drift_bps)
+        {
+            return Err(ErrorCode::RebalanceNotAllowed.into());
+        }
+        let now = Clock::get()?.unix_timestamp;
+        check_keeper_cooldown(
+            strategy_account.last_rebalance_ts,
+            strategy_account.keeper_config.cooldown,
+            now,
+        )?;
+        let (spot, perp) = mango_util::get_hedge_positions(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &strategy_account.market_info,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        let (delta, _band) = mango_util::calculate_delta(
+            spot,
+            perp,
+            strategy_account.target_hedge_ratio_bps,
+            strategy_account.delta_tolerance_bps,
+        );
+        let base_lot_size = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            strategy_account.market_info.perp_market_index as usize,
+        )
+        .map_err(ErrorCode::register_mango_error)?
+        .base_lot_size;
+        let perp_quantity: i64 = (delta.abs() / I80F48::from_num(base_lot_size))
+            .checked_floor()
+            .and_then(|quantity| quantity.checked_to_num())
+            .ok_or(ErrorCode::MathOverflow)?;
+        // delta > 0: perp short too small
+        let (before, after) = public_keeper_trade(
+            ctx.accounts,
+            &bumps,
+            delta < 0,
+            perp_quantity,
+            false,
+            false,
+            I80F48::ZERO,
+        )?;
+        mango_util::record_perp_stats(&mut ctx.accounts.strategy_account.stats, &before, &after)?;
+        let drift_bps_after = get_drift_bps(ctx.accounts)?;
+        let min_improvement = I80F48::from_num(
+            ctx.accounts
+                .strategy_account
+                .keeper_config
+                .min_drift_improvement_bps,
+        );
+        let bounty = if drift_bps_before.saturating_sub(drift_bps_after) >= min_improvement {
+            pay_keeper_bounty(ctx.accounts, &bumps, ctx.remaining_accounts)?
+        } else {
+            0
+        };
+        ctx.accounts.strategy_account.last_rebalance_ts = now;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        emit!(RebalancePublicEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            drift_bps: drift_bps_before.checked_to_num().unwrap_or(i64::MAX),
+            drift_bps_after: drift_bps_after.checked_to_num().unwrap_or(i64::MAX),
+            bounty,
+        });
+        Ok(())
+    }
+
+    /// NAV account for strategies initialized before it existed. Permissionless,
+    /// the NAV is published by the next state-changing instruction or snapshot
+    pub fn create_nav_account(ctx: Context<CreateNavAccount>, bumps: Bumps) -> Result<()> {
+        ctx.accounts.nav_account.load_init()?.strategy_account =
+            ctx.accounts.strategy_account.key();
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Creates the next Mango account (account_num = count + 1), e.g. for another market leg
+    pub fn create_mango_account(ctx: Context<CreateMangoAccount>, bumps: Bumps) -> Result<()> {
+        let account_num = ctx.accounts.strategy_account.mango_account_count + 1;
+        if account_num > MAX_MANGO_ACCOUNTS {
+            return Err(ErrorCode::InvalidMangoAccount.into());
+        }
+        mango_util::create_account(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.owner,
+            &ctx.accounts.system_program,
+            &[&[
+                ctx.accounts.strategy_id.key().as_ref(),
+                STRATEGY_ACCOUNT_PDA_SEED,
+                &[bumps.strategy_account_bump],
+            ]],
+            account_num,
+        )?;
+        ctx.accounts.strategy_account.mango_account_count = account_num;
+        Ok(())
+    }
+
+    /// Grows a strategy account created with the original layout to StrategyAccount::LEN,
+    /// new fields start zeroed (fees, limits and keeper config disabled)
+    pub fn migrate_strategy_account(
+        ctx: Context<MigrateStrategyAccount>,
+        bumps: Bumps,
+    ) -> Result<()> {
+        let _ = bumps; // bumps used in validation
+        let strategy_account = &ctx.accounts.strategy_account;
+        if strategy_account.owner != ctx.program_id
+            || strategy_account.data_len() != LegacyStrategyAccount::LEN
+        {
+            return Err(ErrorCode::InvalidStrategyAccountVersion.into());
+        }
+        let legacy = {
+            let data = strategy_account.try_borrow_data()?;
+            if data[..8] != StrategyAccount::discriminator() {
+                return Err(ErrorCode::InvalidStrategyAccountVersion.into());
+            }
+            LegacyStrategyAccount::deserialize(&mut &data[8..])
+                .map_err(|_| ErrorCode::InvalidStrategyAccountVersion)?
+        };
+        if legacy.owner != ctx.accounts.owner.key() {
+            return Err(ErrorCode::InvalidOwner.into());
+        }
+
+        let rent = Rent::get()?
+            .minimum_balance(StrategyAccount::LEN)
+            .saturating_sub(strategy_account.lamports());
+        if rent > 0 {
+            solana_program::program::invoke(
+                &solana_program::system_instruction::transfer(
+                    &ctx.accounts.owner.key(),
+                    &strategy_account.key(),
+                    rent,
+                ),
+                &[
+                    ctx.accounts.owner.to_owned(),
+                    strategy_account.to_owned(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+            )?;
+        }
+        strategy_account.realloc(StrategyAccount::LEN, true)?;
+
+        let migrated = StrategyAccount {
+            owner: legacy.owner,
+            trigger_server_pk: legacy.trigger_server_pk,
+            vault_token_mint: legacy.vault_token_mint,
+            mango_program: legacy.mango_program,
+            mango_group: legacy.mango_group,
+            limits_account: legacy.limits_account,
+            market_info: legacy.market_info,
+            mango_account_count: MANGO_ACCOUNT_NUM,
+            ..Default::default()
+        };
+        let mut data = strategy_account.try_borrow_mut_data()?;
+        migrated.try_serialize(&mut &mut data[..])?;
+        Ok(())
+    }
+
+    /// Moves USDC between Mango accounts of the strategy through the vault token account
+    pub fn transfer_usdc(ctx: Context<TransferUsdc>, bumps: Bumps, amount: u64) -> Result<()> {
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let bump = &[bumps.strategy_account_bump];
+        let seeds = &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]];
+        mango_util::withdraw_tokens(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.from_mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_root_bank,
+            &ctx.accounts.mango_node_bank,
+            &ctx.accounts.mango_vault,
+            &ctx.accounts.mango_signer,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_token_account.to_account_info(),
+            &ctx.accounts.from_spot_open_orders,
+            seeds,
+            amount,
+            ctx.accounts.strategy_account.market_info.spot_market_index as usize,
+        )?;
+        mango_util::deposit_tokens(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.to_mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_root_bank,
+            &ctx.accounts.mango_node_bank,
+            &ctx.accounts.mango_vault,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.token_program,
+            &ctx.accounts.vault_token_account.to_account_info(),
+            seeds,
+            amount,
+        )?;
+        Ok(())
+    }
+
+    /// Permissionless, emits DeltaEvent. Fails with DeltaOutOfBand if `fail_out_of_band` is set
+    /// and the hedge is outside of the tolerance band
+    pub fn check_delta(
+        ctx: Context<CheckDelta>,
+        bumps: Bumps,
+        fail_out_of_band: bool,
+    ) -> Result<()> {
+        let strategy_account = &ctx.accounts.strategy_account;
+        let (spot, perp) = mango_util::get_hedge_positions(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &strategy_account.market_info,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        let (delta, band) = mango_util::calculate_delta(
+            spot,
+            perp,
+            strategy_account.target_hedge_ratio_bps,
+            strategy_account.delta_tolerance_bps,
+        );
+        let out_of_band = strategy_account.target_hedge_ratio_bps > 0 && delta.abs() > band;
+        if out_of_band && fail_out_of_band {
+            return Err(ErrorCode::DeltaOutOfBand.into());
+        }
+        emit!(DeltaEvent {
+            strategy_account: strategy_account.key(),
+            spot: spot.checked_to_num().unwrap_or(0),
+            perp: perp.checked_to_num().unwrap_or(0),
+            delta: delta.checked_to_num().unwrap_or(0),
+            band: band.checked_to_num().unwrap_or(0),
+            out_of_band,
+        });
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Emits StatsEvent, funding includes accrual since the last perp trade
+    pub fn view_stats(ctx: Context<ViewStats>, bumps: Bumps) -> Result<()> {
+        let snapshot = mango_util::get_perp_snapshot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.accounts.strategy_account.market_info.perp_market_index as usize,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        let stats = &ctx.accounts.strategy_account.stats;
+        let funding_earned = stats
+            .funding_earned
+            .checked_add(mango_util::get_pending_funding(stats, &snapshot)?)
+            .ok_or(ErrorCode::MathOverflow)?;
+        emit!(StatsEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            funding_earned,
+            estimated_fees: stats.estimated_fees,
+            slippage: stats.slippage,
+        });
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// `client_order_id` None cancels all resting perp orders
+    pub fn cancel_perp_orders(
+        ctx: Context<CancelPerpOrders>,
+        bumps: Bumps,
+        client_order_id: Option<u64>,
+    ) -> Result<()> {
+        mango_util::cancel_perp_orders(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+            &[&[
+                ctx.accounts.strategy_id.key().as_ref(),
+                STRATEGY_ACCOUNT_PDA_SEED,
+                &[bumps.strategy_account_bump],
+            ]],
+            client_order_id,
+        )?;
+        Ok(())
+    }
+
+    pub fn adjust_position_spot(
+        ctx: Context<AdjustPositionSpot>,
+        bumps: Bumps,
+        amount: i64,
+    ) -> Result<()> {
+        assert_ne!(amount, 0, "Amount should not be zero");
+        let side = if amount > 0 {
+            serum_dex::matching::Side::Bid
+        } else {
+            serum_dex::matching::Side::Ask
+        };
+        let positions_before = get_position_check(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.spot_open_orders,
+        )?;
+        mango_util::adjust_position_spot(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_signer,
+            &ctx.accounts.serum_dex,
+            &ctx.accounts.spot_market,
+            &ctx.accounts.spot_bids,
+            &ctx.accounts.spot_asks,
+            &ctx.accounts.spot_request_queue,
+            &ctx.accounts.spot_event_queue,
+            &ctx.accounts.spot_base,
+            &ctx.accounts.spot_quote,
+            &ctx.accounts.spot_base_root_bank,
+            &ctx.accounts.spot_base_node_bank,
+            &ctx.accounts.spot_base_vault,
+            &ctx.accounts.spot_quote_root_bank,
+            &ctx.accounts.spot_quote_node_bank,
+            &ctx.accounts.spot_quote_vault,
+            &ctx.accounts.serum_dex_signer,
+            &ctx.accounts.spot_open_orders,
+            &ctx.accounts.srm_vault,
+            &ctx.accounts.token_program,
+            &[&[
+                ctx.accounts.strategy_id.key().as_ref(),
+                STRATEGY_ACCOUNT_PDA_SEED,
+                &[bumps.strategy_account_bump],
+            ]],
+            side,
+            amount.abs() as u64,
+            ctx.accounts
+                .strategy_account
+                .market_info
+                .spot_market_lot_size,
+        )?;
+        check_position_change(
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.spot_open_orders,
+            positions_before,
+        )?;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        Ok(())
+    }
+
+    /// Marks the strategy deprecated in the registry, the strategy itself keeps working
+    pub fn deprecate_strategy(ctx: Context<DeprecateStrategy>, bumps: Bumps) -> Result<()> {
+        let index =
+            (ctx.accounts.strategy_account.registry_index % RegistryPage::CAPACITY) as usize;
+        let entry = ctx
+            .accounts
+            .registry_page
+            .entries
+            .get_mut(index)
+            .filter(|entry| entry.strategy_id == ctx.accounts.strategy_id.key())
+            .ok_or(ErrorCode::NotInRegistry)?;
+        entry.deprecated = true;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Redeems MNGO, withdraws the USDC, spot token and MNGO deposits to the owner, moves what is left
+    /// below one native token to the Mango dust account and closes the last Mango account
+    /// (account_num = mango_account_count) with its open orders, the main account is closed last.
+    /// Perp positions must be flat and perp PnL settled (Mango SettlePnl).
+    pub fn close_mango_account(ctx: Context<CloseMangoAccount>, bumps: Bumps) -> Result<()> {
+        if ctx.accounts.strategy_token_mint.supply != 0 {
+            return Err(ErrorCode::StrategyNotEmpty.into());
+        }
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let bump = &[bumps.strategy_account_bump];
+        let seeds = &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]];
+        let market_info = ctx.accounts.strategy_account.market_info.clone();
+        let (advanced_orders_key, spot_open_orders_key, mngo_accrued) = {
+            let mango_account = MangoAccount::load_checked(
+                &ctx.accounts.mango_account,
+                &ctx.accounts.mango_program.key(),
+                &ctx.accounts.mango_group.key(),
+            )
+            .map_err(ErrorCode::register_mango_error)?;
+            (
+                mango_account.advanced_orders_key,
+                mango_account.spot_open_orders[market_info.spot_market_index as usize],
+                mango_account.perp_accounts[market_info.perp_market_index as usize].mngo_accrued,
+            )
+        };
+        if mngo_accrued > 0 {
+            mango_util::redeem_mngo(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_cache,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.strategy_account.to_account_info(),
+                &ctx.accounts.mango_market,
+                &ctx.accounts.mngo_perp_vault,
+                &ctx.accounts.mngo_root_bank,
+                &ctx.accounts.mngo_node_bank,
+                &ctx.accounts.mngo_vault,
+                &ctx.accounts.mango_signer,
+                &ctx.accounts.token_program,
+                seeds,
+            )?;
+        }
+        if spot_open_orders_key != Pubkey::default() {
+            mango_util::close_open_orders(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.strategy_account.to_account_info(),
+                &ctx.accounts.serum_dex,
+                &ctx.accounts.spot_open_orders,
+                &ctx.accounts.spot_market,
+                &ctx.accounts.mango_signer,
+                seeds,
+            )?;
+        }
+        let mut remaining_accounts = ctx.remaining_accounts.iter();
+        if advanced_orders_key != Pubkey::default() {
+            let advanced_orders = remaining_accounts
+                .next()
+                .filter(|acc| acc.key() == advanced_orders_key)
+                .ok_or(ErrorCode::InvalidAdvancedOrdersAccount)?;
+            mango_util::close_trigger_orders(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.strategy_account.to_account_info(),
+                advanced_orders,
+                seeds,
+            )?;
+        }
+        if let [mngo_spot_market, mngo_spot_open_orders] = remaining_accounts.as_slice() {
+            mango_util::close_open_orders(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.strategy_account.to_account_info(),
+                &ctx.accounts.serum_dex,
+                mngo_spot_open_orders,
+                mngo_spot_market,
+                &ctx.accounts.mango_signer,
+                seeds,
+            )?;
+        }
+        // u64::MAX withdraws the deposit rounded down, mango fails if there are borrows
+        let tokens = [
+            (
+                &ctx.accounts.mango_root_bank,
+                &ctx.accounts.mango_node_bank,
+                &ctx.accounts.mango_vault,
+                ctx.accounts.owner_token_account.to_account_info(),
+            ),
+            (
+                &ctx.accounts.spot_base_root_bank,
+                &ctx.accounts.spot_base_node_bank,
+                &ctx.accounts.spot_base_vault,
+                ctx.accounts.owner_base_token_account.to_account_info(),
+            ),
+            (
+                &ctx.accounts.mngo_root_bank,
+                &ctx.accounts.mngo_node_bank,
+                &ctx.accounts.mngo_vault,
+                ctx.accounts.owner_mngo_token_account.to_account_info(),
+            ),
+        ];
+        for (root_bank, node_bank, vault, token_account) in tokens.iter() {
+            mango_util::withdraw_tokens(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.mango_cache,
+                root_bank,
+                node_bank,
+                vault,
+                &ctx.accounts.mango_signer,
+                &ctx.accounts.strategy_account.to_account_info(),
+                &ctx.accounts.token_program,
+                token_account,
+                &ctx.accounts.spot_open_orders,
+                seeds,
+                u64::MAX,
+                market_info.spot_market_index as usize,
+            )?;
+            mango_util::resolve_dust(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.strategy_account.to_account_info(),
+                &ctx.accounts.dust_account,
+                root_bank,
+                node_bank,
+                &ctx.accounts.mango_cache,
+                seeds,
+            )?;
+        }
+        mango_util::close_account(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            seeds,
+        )?;
+        ctx.accounts.strategy_account.mango_account_count -= 1;
+        Ok(())
+    }
+
+    /// Closes vault, strategy account and its pdas after close_mango_account closed all Mango accounts
+    /// and queued deposits were processed, rent goes to the owner. The registry entry is marked closed.
+    /// Strategy token mint can't be closed.
+    pub fn close_strategy(ctx: Context<CloseStrategy>, bumps: Bumps) -> Result<()> {
+        if ctx.accounts.strategy_token_mint.supply != 0 {
+            return Err(ErrorCode::StrategyNotEmpty.into());
+        }
+        if ctx.accounts.strategy_account.mango_account_count != 0 {
+            return Err(ErrorCode::MangoAccountNotClosed.into());
+        }
+        if ctx.accounts.deposit_queue.owner == ctx.program_id {
+            let deposit_queue = Account::<DepositQueue>::try_from(&ctx.accounts.deposit_queue)?;
+            if deposit_queue.pending_amount != 0 {
+                return Err(ErrorCode::PendingDepositsNotProcessed.into());
+            }
+            deposit_queue.close(ctx.accounts.owner.to_owned())?;
+        }
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let bump = &[bumps.strategy_account_bump];
+        let seeds = &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]];
+        let mut token_accounts = vec![ctx.accounts.vault_token_account.to_account_info()];
+        // USDC sent to the pending vault directly keeps it open
+        if ctx.accounts.pending_vault_token_account.owner == &anchor_spl::token::ID
+            && Account::<TokenAccount>::try_from(&ctx.accounts.pending_vault_token_account)?.amount
+                == 0
+        {
+            token_accounts.push(ctx.accounts.pending_vault_token_account.to_owned());
+        }
+        for token_account in token_accounts {
+            let cpi_accounts = CloseAccount {
+                account: token_account,
+                destination: ctx.accounts.owner.to_account_info(),
+                authority: ctx.accounts.strategy_account.to_account_info(),
+            };
+            let cpi_context = CpiContext::new_with_signer(
+                ctx.accounts.token_program.to_account_info(),
+                cpi_accounts,
+                seeds,
+            );
+            close_account(cpi_context)?;
+        }
+
+        if ctx.accounts.pending_change.owner == ctx.program_id {
+            Account::<PendingChange>::try_from(&ctx.accounts.pending_change)?
+                .close(ctx.accounts.owner.to_owned())?;
+        }
+        let delegate_lamports = ctx.accounts.trigger_delegate.lamports();
+        if delegate_lamports > 0 {
+            let delegate_bump = &[trigger_delegate_bump(&strategy_id, ctx.program_id)];
+            solana_program::program::invoke_signed(
+                &solana_program::system_instruction::transfer(
+                    &ctx.accounts.trigger_delegate.key(),
+                    &ctx.accounts.owner.key(),
+                    delegate_lamports,
+                ),
+                &[
+                    ctx.accounts.trigger_delegate.to_owned(),
+                    ctx.accounts.owner.to_owned(),
+                    ctx.accounts.system_program.to_account_info(),
+                ],
+                &[&[
+                    strategy_id.as_ref(),
+                    TRIGGER_DELEGATE_PDA_SEED,
+                    &delegate_bump[..],
+                ][..]],
+            )?;
+        }
+        // strategies migrated from the original layout aren't listed
+        let index =
+            (ctx.accounts.strategy_account.registry_index % RegistryPage::CAPACITY) as usize;
+        if let Some(entry) = ctx
+            .accounts
+            .registry_page
+            .entries
+            .get_mut(index)
+            .filter(|entry| entry.strategy_id == strategy_id)
+        {
+            entry.deprecated = true;
+            entry.closed = true;
+        }
+        Ok(())
+    }
+
+    /// Creates the advanced orders account, owned by the trigger delegate pda
+    /// (strategy account holds data and can't pay for it)
+    pub fn init_trigger_orders(ctx: Context<TriggerOrders>, bumps: Bumps) -> Result<()> {
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let rent = Rent::get()?;
+        let lamports =
+            rent.minimum_balance(std::mem::size_of::<AdvancedOrders>()) + rent.minimum_balance(0);
+        fund_trigger_delegate(&ctx.accounts, lamports)?;
+        let bump = &[bumps.strategy_account_bump];
+        mango_util::set_account_delegate(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.trigger_delegate,
+            &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]],
+        )?;
+        let delegate_bump = &[trigger_delegate_bump(&strategy_id, ctx.program_id)];
+        mango_util::create_advanced_orders(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.trigger_delegate,
+            &ctx.accounts.advanced_orders,
+            &ctx.accounts.system_program.to_account_info(),
+            &[&[
+                strategy_id.as_ref(),
+                TRIGGER_DELEGATE_PDA_SEED,
+                &delegate_bump[..],
+            ][..]],
+        )?;
+        Ok(())
+    }
+
+    /// Reduce only perp order executed by anyone when oracle price crosses `trigger_price`,
+    /// amount > 0: bid (stop loss / take profit of a short), amount < 0: ask.
+    /// `price` - worst price in quote lots per base lot, `trigger_price` - I80F48 bits (native quote per native base)
+    pub fn add_trigger_order(
+        ctx: Context<TriggerOrders>,
+        bumps: Bumps,
+        amount: i64,
+        price: i64,
+        trigger_condition: TriggerCondition,
+        trigger_price: i128,
+        client_order_id: u64,
+    ) -> Result<()> {
+        assert_ne!(amount, 0, "Amount should not be zero");
+        let side = if amount > 0 {
+            mango::matching::Side::Bid
+        } else {
+            mango::matching::Side::Ask
+        };
+        fund_trigger_delegate(&ctx.accounts, ADVANCED_ORDER_FEE)?;
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let delegate_bump = &[trigger_delegate_bump(&strategy_id, ctx.program_id)];
+        mango_util::add_trigger_order(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.trigger_delegate,
+            &ctx.accounts.advanced_orders,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.system_program.to_account_info(),
+            &ctx.accounts.spot_open_orders,
+            &[&[
+                strategy_id.as_ref(),
+                TRIGGER_DELEGATE_PDA_SEED,
+                &delegate_bump[..],
+            ][..]],
+            side,
+            trigger_condition.into(),
+            client_order_id,
+            price,
+            amount.abs(),
+            I80F48::from_bits(trigger_price),
+        )?;
+        sync_perp_stats(ctx.accounts)?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Execution fee is refunded to the trigger delegate
+    pub fn remove_trigger_order(
+        ctx: Context<TriggerOrders>,
+        bumps: Bumps,
+        order_index: u8,
+    ) -> Result<()> {
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let delegate_bump = &[trigger_delegate_bump(&strategy_id, ctx.program_id)];
+        mango_util::remove_trigger_order(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.trigger_delegate,
+            &ctx.accounts.advanced_orders,
+            &ctx.accounts.system_program.to_account_info(),
+            &[&[
+                strategy_id.as_ref(),
+                TRIGGER_DELEGATE_PDA_SEED,
+                &delegate_bump[..],
+            ][..]],
+            order_index,
+        )?;
+        sync_perp_stats(ctx.accounts)?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Emits TriggerOrderEvent for every active trigger order
+    pub fn list_trigger_orders(ctx: Context<ListTriggerOrders>, bumps: Bumps) -> Result<()> {
+        let orders = mango_util::get_trigger_orders(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.advanced_orders,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        for (order_index, order) in orders {
+            emit!(TriggerOrderEvent {
+                strategy_account: ctx.accounts.strategy_account.key(),
+                order_index,
+                amount: match order.side {
+                    mango::matching::Side::Bid => order.quantity,
+                    mango::matching::Side::Ask => -order.quantity,
+                },
+                price: order.price,
+                trigger_condition: match order.trigger_condition {
+                    mango::state::TriggerCondition::Above => TriggerCondition::Above,
+                    mango::state::TriggerCondition::Below => TriggerCondition::Below,
+                },
+                trigger_price: order.trigger_price.to_bits(),
+                client_order_id: order.client_order_id,
+            });
+        }
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Redeems accrued MNGO and sells it for USDC on the MNGO spot market,
+    /// the sale price is at most `max_slippage_bps` below the oracle price
+    pub fn harvest_mngo(
+        ctx: Context<HarvestMngo>,
+        bumps: Bumps,
+        max_slippage_bps: u16,
+    ) -> Result<()> {
+        if max_slippage_bps as u64 > BPS_DENOMINATOR {
+            return Err(ErrorCode::InvalidSlippage.into());
+        }
+        let strategy_id = ctx.accounts.strategy_id.key();
+        let bump = &[bumps.strategy_account_bump];
+        let seeds = &[&[strategy_id.as_ref(), STRATEGY_ACCOUNT_PDA_SEED, &bump[..]][..]];
+        if ctx.accounts.mngo_spot_open_orders.data_is_empty() {
+            mango_util::create_open_orders(
+                &ctx.accounts.mango_program,
+                &ctx.accounts.mango_group,
+                &ctx.accounts.mango_account,
+                &ctx.accounts.strategy_account.to_account_info(),
+                &ctx.accounts.serum_dex,
+                &ctx.accounts.mngo_spot_open_orders,
+                &ctx.accounts.spot_market,
+                &ctx.accounts.mango_signer,
+                &ctx.accounts.trigger_server,
+                &ctx.accounts.system_program,
+                seeds,
+            )?;
+        }
+        mango_util::redeem_mngo(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mngo_perp_vault,
+            &ctx.accounts.spot_base_root_bank,
+            &ctx.accounts.spot_base_node_bank,
+            &ctx.accounts.spot_base_vault,
+            &ctx.accounts.mango_signer,
+            &ctx.accounts.token_program,
+            seeds,
+        )?;
+        let order = mango_util::get_spot_sell_order(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.serum_dex,
+            &ctx.accounts.spot_market,
+            max_slippage_bps,
+        )?;
+        let (limit_price, base_lots) = match order {
+            Some(order) => order,
+            None => return Ok(()),
+        };
+        let usdc_before = mango_util::get_native_deposit(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            USDC_TOKEN_INDEX,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        mango_util::place_spot_order(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.strategy_account.to_account_info(),
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_signer,
+            &ctx.accounts.serum_dex,
+            &ctx.accounts.spot_market,
+            &ctx.accounts.spot_bids,
+            &ctx.accounts.spot_asks,
+            &ctx.accounts.spot_request_queue,
+            &ctx.accounts.spot_event_queue,
+            &ctx.accounts.spot_base,
+            &ctx.accounts.spot_quote,
+            &ctx.accounts.spot_base_root_bank,
+            &ctx.accounts.spot_base_node_bank,
+            &ctx.accounts.spot_base_vault,
+            &ctx.accounts.spot_quote_root_bank,
+            &ctx.accounts.spot_quote_node_bank,
+            &ctx.accounts.spot_quote_vault,
+            &ctx.accounts.serum_dex_signer,
+            &[
+                ctx.accounts.spot_open_orders.to_owned(),
+                ctx.accounts.mngo_spot_open_orders.to_owned(),
+            ],
+            &ctx.accounts.srm_vault,
+            &ctx.accounts.token_program,
+            seeds,
+            serum_dex::matching::Side::Ask,
+            limit_price,
+            base_lots,
+            u64::MAX,
+        )?;
+        let usdc_after = mango_util::get_native_deposit(
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            USDC_TOKEN_INDEX,
+        )
+        .map_err(ErrorCode::register_mango_error)?;
+        refresh_nav(
+            &ctx.accounts.nav_account,
+            &ctx.accounts.strategy_account,
+            &ctx.accounts.strategy_token_mint,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_account,
+            &ctx.accounts.mango_cache,
+            ctx.remaining_accounts,
+        )?;
+        emit!(HarvestMngoEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            usdc_amount: (usdc_after - usdc_before).checked_to_num().unwrap_or(0),
+        });
+        Ok(())
+    }
+
+    /// With a timelock limits are created and updated with propose_change / execute_change
+    pub fn set_limits(
+        ctx: Context<SetLimits>,
+        bumps: Bumps,
+        max_tvl: Option<u64>,
+        max_deposit: u64,
+        whitelist: Vec<WhitelistLimit>,
+    ) -> ProgramResult {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        ctx.accounts.limits_account.max_tvl = max_tvl;
+        ctx.accounts.limits_account.max_deposit = max_deposit;
+        ctx.accounts.limits_account.whitelist = whitelist;
+        ctx.accounts.strategy_account.limits_account = Some(ctx.accounts.limits_account.key());
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    pub fn drop_limits(ctx: Context<DropLimits>, bumps: Bumps) -> ProgramResult {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        ctx.accounts.strategy_account.limits_account = None;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    pub fn set_min_holding_period(
+        ctx: Context<SetMinHoldingPeriod>,
+        bumps: Bumps,
+        min_holding_period: i64,
+        early_exit_fee_bps: u16,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::MinHoldingPeriod {
+                min_holding_period,
+                early_exit_fee_bps,
+            },
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// max_outflow_bps = 0 disables the limit
+    pub fn set_outflow_limit(
+        ctx: Context<SetOutflowLimit>,
+        bumps: Bumps,
+        max_outflow_bps: u16,
+        window: i64,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::OutflowLimit {
+                max_outflow_bps,
+                window,
+            },
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// In base lots, resting orders included. 0 - no limit
+    pub fn set_max_perp_position(
+        ctx: Context<SetMaxPerpPosition>,
+        bumps: Bumps,
+        max_perp_position: u64,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::MaxPerpPosition(max_perp_position),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Keeper trades can only unwind positions against the new mode
+    pub fn set_mode(ctx: Context<SetMode>, bumps: Bumps, mode: BasisMode) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::Mode(mode),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Spot value / TVL in bps, up to MAX_LEVERAGE_BPS. 0 or 10_000 - no USDC borrowing
+    pub fn set_max_leverage(
+        ctx: Context<SetMaxLeverage>,
+        bumps: Bumps,
+        max_leverage_bps: u32,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::MaxLeverage(max_leverage_bps),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    pub fn set_keeper_config(
+        ctx: Context<SetKeeperConfig>,
+        bumps: Bumps,
+        keeper_config: KeeperConfig,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::KeeperConfig(keeper_config),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Max deviation of the perp book mid from the oracle price, 0 - no limit.
+    /// Perp orders fail with CircuitBreakerTripped while it is exceeded
+    pub fn set_max_book_deviation(
+        ctx: Context<SetMaxBookDeviation>,
+        bumps: Bumps,
+        max_book_deviation_bps: u16,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::MaxBookDeviation(max_book_deviation_bps),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Permissionless, records the tripped circuit breaker (failed perp orders can't persist it),
+    /// perp orders then fail for CIRCUIT_BREAKER_COOLDOWN even if the book recovers
+    pub fn trip_circuit_breaker(ctx: Context<TripCircuitBreaker>, bumps: Bumps) -> Result<()> {
+        let strategy_account = &ctx.accounts.strategy_account;
+        let event = get_book_deviation(
+            strategy_account,
+            &ctx.accounts.mango_program,
+            &ctx.accounts.mango_group,
+            &ctx.accounts.mango_cache,
+            &ctx.accounts.mango_market,
+            &ctx.accounts.mango_bids,
+            &ctx.accounts.mango_asks,
+        )?
+        .ok_or(ErrorCode::CircuitBreakerNotTripped)?;
+        ctx.accounts.strategy_account.circuit_breaker_tripped_at = Clock::get()?.unix_timestamp;
+        emit!(event);
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Clears a tripped circuit breaker before the cooldown ends
+    pub fn reset_circuit_breaker(ctx: Context<ResetCircuitBreaker>, bumps: Bumps) -> Result<()> {
+        ctx.accounts.strategy_account.circuit_breaker_tripped_at = 0;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Annualised, in bps, of the funding earned in the current mode. None - no minimum
+    pub fn set_min_funding_rate(
+        ctx: Context<SetMinFundingRate>,
+        bumps: Bumps,
+        min_funding_rate_bps: Option<i32>,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::MinFundingRate(min_funding_rate_bps),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Ratio and tolerance in bps, ratio 0 disables delta checks
+    pub fn set_hedge_config(
+        ctx: Context<SetHedgeConfig>,
+        bumps: Bumps,
+        target_hedge_ratio_bps: u16,
+        delta_tolerance_bps: u16,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::HedgeConfig {
+                target_hedge_ratio_bps,
+                delta_tolerance_bps,
+            },
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    pub fn set_fees(
+        ctx: Context<SetFees>,
+        bumps: Bumps,
+        deposit_fee_bps: u16,
+        withdraw_fee_bps: u16,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::Fees {
+                deposit_fee_bps,
+                withdraw_fee_bps,
+            },
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Enables the timelock, after that the delay can only be changed through propose_change
+    pub fn set_timelock_delay(
+        ctx: Context<SetTimelockDelay>,
+        bumps: Bumps,
+        timelock_delay: i64,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::TimelockDelay(timelock_delay),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Seconds between process_deposits, 0 - direct deposits. Queued deposits stay processable
+    pub fn set_deposit_epoch(
+        ctx: Context<SetDepositEpoch>,
+        bumps: Bumps,
+        deposit_epoch: i64,
+    ) -> Result<()> {
+        check_no_timelock(&ctx.accounts.strategy_account)?;
+        apply_change(
+            &mut ctx.accounts.strategy_account,
+            &ParameterChange::DepositEpoch(deposit_epoch),
+        )?;
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    /// Queues a change executable after the current timelock delay, one at a time
+    pub fn propose_change(
+        ctx: Context<ProposeChange>,
+        bumps: Bumps,
+        change: ParameterChange,
+    ) -> Result<()> {
+        validate_change(&change)?;
+        let proposed_at = Clock::get()?.unix_timestamp;
+        let executable_at = proposed_at + ctx.accounts.strategy_account.timelock_delay;
+        let pending_change = &mut ctx.accounts.pending_change;
+        pending_change.change = change.clone();
+        pending_change.proposed_at = proposed_at;
+        pending_change.executable_at = executable_at;
+        emit!(ChangeProposedEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            change,
+            executable_at,
+        });
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    pub fn execute_change<'info>(
+        ctx: Context<'_, '_, '_, 'info, ExecuteChange<'info>>,
+        bumps: Bumps,
+    ) -> Result<()> {
+        if Clock::get()?.unix_timestamp < ctx.accounts.pending_change.executable_at {
+            return Err(ErrorCode::TimelockNotElapsed.into());
+        }
+        let change = ctx.accounts.pending_change.change.clone();
+        match &change {
+            ParameterChange::Limits {
+                max_tvl,
+                max_deposit,
+                whitelist,
+            } => {
+                let mut limits_account = if ctx.accounts.strategy_account.limits_account.is_some() {
+                    load_limits_account(&ctx.accounts.strategy_account, ctx.remaining_accounts)?
+                } else {
+                    create_limits_account(
+                        &ctx.accounts.owner,
+                        &ctx.accounts.system_program,
+                        ctx.remaining_accounts,
+                        ctx.program_id,
+                    )?
+                };
+                limits_account.max_tvl = *max_tvl;
+                limits_account.max_deposit = *max_deposit;
+                limits_account.whitelist = whitelist.clone();
+                limits_account.exit(ctx.program_id)?;
+                ctx.accounts.strategy_account.limits_account = Some(limits_account.key());
+            }
+            ParameterChange::DropLimits => {
+                let limits_account =
+                    load_limits_account(&ctx.accounts.strategy_account, ctx.remaining_accounts)?;
+                limits_account.close(ctx.accounts.owner.to_owned())?;
+                ctx.accounts.strategy_account.limits_account = None;
+            }
+            change => apply_change(&mut ctx.accounts.strategy_account, change)?,
+        }
+        emit!(ChangeExecutedEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            change,
+        });
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+
+    pub fn cancel_change(ctx: Context<CancelChange>, bumps: Bumps) -> Result<()> {
+        emit!(ChangeCancelledEvent {
+            strategy_account: ctx.accounts.strategy_account.key(),
+            change: ctx.accounts.pending_change.change.clone(),
+        });
+        let _ = bumps; // bumps used in validation
+        Ok(())
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, Default)]
+pub struct MarketInfo {
+    pub perp_market_index: u8,
+    pub spot_market_index: u8,
+    pub spot_market_lot_size: u64,
+    pub spot_token_index: u8,
+}
+
+/// Normal: long spot, short perp. Reverse: borrowed spot sold, long perp
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BasisMode {
+    Normal,
+    Reverse,
+}
+
+impl Default for BasisMode {
+    fn default() -> Self {
+        BasisMode::Normal
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub enum MakerOrderType {
+    PostOnly,
+    PostOnlySlide,
+}
+
+impl From<MakerOrderType> for mango::matching::OrderType {
+    fn from(order_type: MakerOrderType) -> Self {
+        match order_type {
+            MakerOrderType::PostOnly => mango::matching::OrderType::PostOnly,
+            MakerOrderType::PostOnlySlide => mango::matching::OrderType::PostOnlySlide,
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug)]
+pub enum TriggerCondition {
+    Above,
+    Below,
+}
+
+impl From<TriggerCondition> for mango::state::TriggerCondition {
+    fn from(trigger_condition: TriggerCondition) -> Self {
+        match trigger_condition {
+            TriggerCondition::Above => mango::state::TriggerCondition::Above,
+            TriggerCondition::Below => mango::state::TriggerCondition::Below,
+        }
+    }
+}
+
+fn trigger_delegate_bump(strategy_id: &Pubkey, program_id: &Pubkey) -> u8 {
+    Pubkey::find_program_address(
+        &[
+            strategy_id.as_ref(),
+            mango_strategy::TRIGGER_DELEGATE_PDA_SEED,
+        ],
+        program_id,
+    )
+    .1
+}
+
+/// Transfers lamports from authority to the trigger delegate
+fn fund_trigger_delegate(accounts: &TriggerOrders<'_>, lamports: u64) -> Result<()> {
+    let instruction = solana_program::system_instruction::transfer(
+        &accounts.authority.key(),
+        &accounts.trigger_delegate.key(),
+        lamports,
+    );
+    solana_program::program::invoke(
+        &instruction,
+        &[
+            accounts.authority.to_owned(),
+            accounts.trigger_delegate.to_owned(),
+            accounts.system_program.to_account_info(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Hedge state compared before and after keeper trades
+struct PositionCheck {
+    /// native base by which the hedge is outside of the tolerance band, <= 0 inside or if not configured
+    delta_excess: I80F48,
+    /// native base, spot borrow / perp long in normal mode, spot deposit / perp short in reverse mode
+    counter_mode_exposure: I80F48,
+    leverage: I80F48,
+    init_health: I80F48,
+}
+
+fn get_position_check<'info>(
+    strategy_account: &StrategyAccount,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+) -> Result<PositionCheck> {
+    let market_info = &strategy_account.market_info;
+    let (spot, perp) = mango_util::get_hedge_positions(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_info,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    let delta_excess = if strategy_account.target_hedge_ratio_bps == 0 {
+        I80F48::ZERO
+    } else {
+        let (delta, band) = mango_util::calculate_delta(
+            spot,
+            perp,
+            strategy_account.target_hedge_ratio_bps,
+            strategy_account.delta_tolerance_bps,
+        );
+        delta.abs() - band
+    };
+    let counter_mode_exposure = match strategy_account.mode {
+        BasisMode::Normal => (-spot).max(I80F48::ZERO) + perp.max(I80F48::ZERO),
+        BasisMode::Reverse => spot.max(I80F48::ZERO) + (-perp).max(I80F48::ZERO),
+    };
+    let leverage = mango_util::calculate_leverage(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_info,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    let init_health = mango_util::get_health(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        spot_open_orders,
+        market_info,
+        HealthType::Init,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    Ok(PositionCheck {
+        delta_excess,
+        counter_mode_exposure,
+        leverage,
+        init_health,
+    })
+}
+
+/// Fails if a trade left the hedge outside of the tolerance band and further out than before,
+/// increased the position against the mode, increased leverage above the max
+/// or decreased negative init health
+fn check_position_change<'info>(
+    strategy_account: &StrategyAccount,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+    before: PositionCheck,
+) -> Result<()> {
+    let after = get_position_check(
+        strategy_account,
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        spot_open_orders,
+    )?;
+    if after.delta_excess > 0 && after.delta_excess > before.delta_excess {
+        return Err(ErrorCode::DeltaOutOfBand.into());
+    }
+    if after.counter_mode_exposure > before.counter_mode_exposure {
+        return Err(ErrorCode::InvalidModeDirection.into());
+    }
+    // 0 - no borrowing, 1x
+    let max_leverage = I80F48::from_num(
+        strategy_account
+            .max_leverage_bps
+            .max(BPS_DENOMINATOR as u32),
+    ) / I80F48::from_num(BPS_DENOMINATOR);
+    if after.leverage > max_leverage && after.leverage > before.leverage {
+        return Err(ErrorCode::LeverageTooHigh.into());
+    }
+    if after.init_health < 0 && after.init_health < before.init_health {
+        return Err(ErrorCode::LeverageTooHigh.into());
+    }
+    Ok(())
+}
+
+/// Appends the current share price to the history, at most once per interval
+fn record_share_price(
+    history: &mut SharePriceHistory,
+    strategy_token_mint: &Account<'_, Mint>,
+    tvl: I80F48,
+) -> Result<bool> {
+    let share_price = mango_util::calculate_token_price(strategy_token_mint.supply, tvl)?;
+    Ok(history.push(SharePriceSnapshot {
+        timestamp: Clock::get()?.unix_timestamp,
+        share_price: share_price.to_bits(),
+        tvl: tvl.checked_to_num().unwrap_or(0),
+    }))
+}
+
+/// Trigger orders fill between instructions, moves the recorded position to Mango's
+fn sync_perp_stats(accounts: &mut TriggerOrders<'_>) -> Result<()> {
+    let snapshot = mango_util::get_perp_snapshot(
+        &accounts.mango_program,
+        &accounts.mango_group,
+        &accounts.mango_account,
+        &accounts.mango_cache,
+        accounts.strategy_account.market_info.perp_market_index as usize,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    mango_util::record_perp_stats(&mut accounts.strategy_account.stats, &snapshot, &snapshot)?;
+    Ok(())
+}
+
+/// IOC perp and spot orders within the keeper slippage bound,
+/// quantities in base lots (perp) and native tokens (spot), with the same guards as owner trades.
+/// Returns perp snapshots before and after for the stats
+fn public_keeper_trade(
+    accounts: &PublicKeeper<'_>,
+    bumps: &Bumps,
+    perp_buy: bool,
+    perp_quantity: i64,
+    perp_reduce_only: bool,
+    spot_buy: bool,
+    spot_quantity: I80F48,
+) -> Result<(PerpSnapshot, PerpSnapshot)> {
+    let strategy_id = accounts.strategy_id.key();
+    let bump = &[bumps.strategy_account_bump];
+    let seeds = &[&[
+        strategy_id.as_ref(),
+        mango_strategy::STRATEGY_ACCOUNT_PDA_SEED,
+        &bump[..],
+    ][..]];
+    let max_slippage_bps = accounts.strategy_account.keeper_config.max_slippage_bps;
+    let perp_market_index = accounts.strategy_account.market_info.perp_market_index as usize;
+    // Reduce-only trades (deleverage) skip the circuit breaker and the position change check:
+    // they shrink both legs, so exposure and leverage can't grow, and neither a dislocated book
+    // nor lot rounding of the delta should keep the strategy near liquidation.
+    // Their prices are still bounded by the oracle and the keeper slippage
+    let positions_before = if perp_reduce_only {
+        None
+    } else {
+        Some(get_position_check(
+            &accounts.strategy_account,
+            &accounts.mango_program,
+            &accounts.mango_group,
+            &accounts.mango_account,
+            &accounts.mango_cache,
+            &accounts.spot_open_orders,
+        )?)
+    };
+    let before = mango_util::get_perp_snapshot(
+        &accounts.mango_program,
+        &accounts.mango_group,
+        &accounts.mango_account,
+        &accounts.mango_cache,
+        perp_market_index,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    if perp_quantity > 0 {
+        let side = if perp_buy {
+            mango::matching::Side::Bid
+        } else {
+            mango::matching::Side::Ask
+        };
+        if !perp_reduce_only {
+            check_circuit_breaker(
+                &accounts.strategy_account,
+                &accounts.mango_program,
+                &accounts.mango_group,
+                &accounts.mango_cache,
+                &accounts.mango_market,
+                &accounts.mango_bids,
+                &accounts.mango_asks,
+            )?;
+        }
+        let perp_reduce_only = check_funding_rate(
+            &accounts.strategy_account,
+            &accounts.mango_program,
+            &accounts.mango_group,
+            &accounts.mango_account,
+            &accounts.mango_cache,
+            &accounts.mango_market,
+            &accounts.mango_bids,
+            &accounts.mango_asks,
+            side,
+        )? || perp_reduce_only;
+        if !perp_reduce_only {
+            check_perp_position_limit(
+                &accounts.strategy_account,
+                &accounts.mango_program,
+                &accounts.mango_group,
+                &accounts.mango_account,
+                side,
+                perp_quantity,
+            )?;
+        }
+        let price = mango_util::get_perp_limit_price(
+            &accounts.mango_program,
+            &accounts.mango_group,
+            &accounts.mango_cache,
+            perp_market_index,
+            side,
+            max_slippage_bps,
+        )?;
+        mango_util::place_perp_ioc_order(
+            &accounts.mango_program,
+            &accounts.mango_group,
+            &accounts.mango_account,
+            &accounts.strategy_account.to_account_info(),
+            &accounts.mango_cache,
+            &accounts.mango_market,
+            &accounts.mango_bids,
+            &accounts.mango_asks,
+            &accounts.mango_event_queue,
+            &accounts.spot_open_orders,
+            seeds,
+            side,
+            price,
+            perp_quantity,
+            perp_market_index,
+            perp_reduce_only,
+        )?;
+    }
+    let side = if spot_buy {
+        serum_dex::matching::Side::Bid
+    } else {
+        serum_dex::matching::Side::Ask
+    };
+    let spot_order = mango_util::get_spot_order(
+        &accounts.mango_program,
+        &accounts.mango_group,
+        &accounts.mango_cache,
+        &accounts.serum_dex,
+        &accounts.spot_market,
+        side,
+        spot_quantity,
+        max_slippage_bps,
+    )?;
+    if let Some((limit_price, base_lots, max_quote_quantity)) = spot_order {
+        mango_util::place_spot_order(
+            &accounts.mango_program,
+            &accounts.mango_group,
+            &accounts.mango_account,
+            &accounts.strategy_account.to_account_info(),
+            &accounts.mango_cache,
+            &accounts.mango_signer,
+            &accounts.serum_dex,
+            &accounts.spot_market,
+            &accounts.spot_bids,
+            &accounts.spot_asks,
+            &accounts.spot_request_queue,
+            &accounts.spot_event_queue,
+            &accounts.spot_base,
+            &accounts.spot_quote,
+            &accounts.spot_base_root_bank,
+            &accounts.spot_base_node_bank,
+            &accounts.spot_base_vault,
+            &accounts.spot_quote_root_bank,
+            &accounts.spot_quote_node_bank,
+            &accounts.spot_quote_vault,
+            &accounts.serum_dex_signer,
+            &[accounts.spot_open_orders.to_owned()],
+            &accounts.srm_vault,
+            &accounts.token_program,
+            seeds,
+            side,
+            limit_price,
+            base_lots,
+            max_quote_quantity,
+        )?;
+    }
+    let after = mango_util::get_perp_snapshot(
+        &accounts.mango_program,
+        &accounts.mango_group,
+        &accounts.mango_account,
+        &accounts.mango_cache,
+        perp_market_index,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    if let Some(positions_before) = positions_before {
+        check_position_change(
+            &accounts.strategy_account,
+            &accounts.mango_program,
+            &accounts.mango_group,
+            &accounts.mango_account,
+            &accounts.mango_cache,
+            &accounts.spot_open_orders,
+            positions_before,
+        )?;
+    }
+    Ok((before, after))
+}
+
+/// |spot * target ratio + perp| / |spot| in bps, I80F48::MAX without spot
+fn get_drift_bps(accounts: &PublicKeeper<'_>) -> Result<I80F48> {
+    let strategy_account = &accounts.strategy_account;
+    let (spot, perp) = mango_util::get_hedge_positions(
+        &accounts.mango_program,
+        &accounts.mango_group,
+        &accounts.mango_account,
+        &accounts.mango_cache,
+        &strategy_account.market_info,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    if spot.is_zero() {
+        return Ok(I80F48::MAX);
+    }
+    let (delta, _band) = mango_util::calculate_delta(
+        spot,
+        perp,
+        strategy_account.target_hedge_ratio_bps,
+        strategy_account.delta_tolerance_bps,
+    );
+    Ok(delta.abs() / spot.abs() * I80F48::from_num(BPS_DENOMINATOR))
+}
+
+/// TVL of the main Mango account plus the value of additional Mango accounts,
+/// which have to be passed in `remaining_accounts`
+fn calculate_strategy_tvl<'info>(
+    strategy_account: &Account<'info, StrategyAccount>,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<I80F48> {
+    let mango_account_address = |account_num: u64| {
+        Pubkey::find_program_address(
+            &[
+                mango_group.key().as_ref(),
+                strategy_account.key().as_ref(),
+                &account_num.to_le_bytes(),
+            ],
+            &mango_program.key(),
+        )
+        .0
+    };
+    // the main account is valued below, passing it again would count it twice
+    let main_address = mango_account_address(mango_strategy::MANGO_ACCOUNT_NUM);
+    if mango_account.key() != main_address
+        || remaining_accounts
+            .iter()
+            .any(|acc| acc.key() == main_address)
+    {
+        return Err(ErrorCode::InvalidMangoAccount.into());
+    }
+    let mut tvl = mango_util::calculate_tvl(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        &strategy_account.market_info,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    for account_num in mango_strategy::MANGO_ACCOUNT_NUM + 1..=strategy_account.mango_account_count
+    {
+        let address = mango_account_address(account_num);
+        let mut matching = remaining_accounts.iter().filter(|acc| acc.key() == address);
+        let account = matching.next().ok_or(ErrorCode::InvalidMangoAccount)?;
+        if matching.next().is_some() {
+            return Err(ErrorCode::InvalidMangoAccount.into());
+        }
+        tvl +=
+            mango_util::calculate_account_value(mango_program, mango_group, account, mango_cache)
+                .map_err(ErrorCode::register_mango_error)?;
+    }
+    Ok(tvl)
+}
+
+/// Fails within `cooldown` seconds after the last call
+fn check_keeper_cooldown(last_ts: i64, cooldown: i64, now: i64) -> Result<()> {
+    if now < last_ts.saturating_add(cooldown) {
+        return Err(ErrorCode::KeeperCooldown.into());
+    }
+    Ok(())
+}
+
+/// Mints strategy tokens worth the keeper bounty to the caller, returns the amount
+fn pay_keeper_bounty<'info>(
+    accounts: &PublicKeeper<'info>,
+    bumps: &Bumps,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<u64> {
+    let tvl = calculate_strategy_tvl(
+        &accounts.strategy_account,
+        &accounts.mango_program,
+        &accounts.mango_group,
+        &accounts.mango_account,
+        &accounts.mango_cache,
+        remaining_accounts,
+    )?;
+    let strategy_token_amount = calculate_shares_for_deposit(
+        accounts.strategy_token_mint.supply,
+        tvl,
+        accounts.strategy_account.keeper_config.bounty,
+    )?;
+    if strategy_token_amount == 0 {
+        return Ok(0);
+    }
+    let strategy_id = accounts.strategy_id.key();
+    let bump = &[bumps.strategy_account_bump];
+    let seeds = &[&[
+        strategy_id.as_ref(),
+        mango_strategy::STRATEGY_ACCOUNT_PDA_SEED,
+        &bump[..],
+    ][..]];
+    let cpi_accounts = MintTo {
+        mint: accounts.strategy_token_mint.to_account_info(),
+        to: accounts.caller_strategy_token_account.to_account_info(),
+        authority: accounts.strategy_account.to_account_info(),
+    };
+    let cpi_context = CpiContext::new_with_signer(
+        accounts.token_program.to_account_info(),
+        cpi_accounts,
+        seeds,
+    );
+    mint_to(cpi_context, strategy_token_amount)?;
+    Ok(strategy_token_amount)
+}
+
+/// Some if the book mid deviates from the oracle price by more than the limit or a book side is empty
+fn get_book_deviation<'info>(
+    strategy_account: &Account<'info, StrategyAccount>,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    mango_bids: &AccountInfo<'info>,
+    mango_asks: &AccountInfo<'info>,
+) -> Result<Option<CircuitBreakerTripped>> {
+    let max_deviation_bps = strategy_account.max_book_deviation_bps;
+    if max_deviation_bps == 0 {
+        return Ok(None);
+    }
+    let (book_mid, oracle_price) = mango_util::get_book_mid_and_oracle_price(
+        mango_program,
+        mango_group,
+        mango_cache,
+        mango_market,
+        mango_bids,
+        mango_asks,
+        strategy_account.market_info.perp_market_index as usize,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    let max_deviation =
+        oracle_price * I80F48::from_num(max_deviation_bps) / I80F48::from_num(BPS_DENOMINATOR);
+    if let Some(book_mid) = book_mid {
+        if (book_mid - oracle_price).abs() <= max_deviation {
+            return Ok(None);
+        }
+    }
+    Ok(Some(CircuitBreakerTripped {
+        strategy_account: strategy_account.key(),
+        book_mid: book_mid.map(I80F48::to_bits),
+        oracle_price: oracle_price.to_bits(),
+        max_deviation_bps,
+    }))
+}
+
+/// Fails while the book deviates or within the cooldown after trip_circuit_breaker
+fn check_circuit_breaker<'info>(
+    strategy_account: &Account<'info, StrategyAccount>,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    mango_bids: &AccountInfo<'info>,
+    mango_asks: &AccountInfo<'info>,
+) -> Result<()> {
+    let tripped_at = strategy_account.circuit_breaker_tripped_at;
+    if tripped_at != 0
+        && Clock::get()?.unix_timestamp
+            < tripped_at.saturating_add(mango_strategy::CIRCUIT_BREAKER_COOLDOWN)
+    {
+        return Err(ErrorCode::CircuitBreakerTripped.into());
+    }
+    let deviation = get_book_deviation(
+        strategy_account,
+        mango_program,
+        mango_group,
+        mango_cache,
+        mango_market,
+        mango_bids,
+        mango_asks,
+    )?;
+    if deviation.is_some() {
+        return Err(ErrorCode::CircuitBreakerTripped.into());
+    }
+    Ok(())
+}
+
+/// Below the minimum funding rate perp orders may only reduce the position,
+/// returns true if the order has to be reduce only
+fn check_funding_rate<'info>(
+    strategy_account: &StrategyAccount,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    mango_bids: &AccountInfo<'info>,
+    mango_asks: &AccountInfo<'info>,
+    side: mango::matching::Side,
+) -> Result<bool> {
+    let min_funding_rate_bps = match strategy_account.min_funding_rate_bps {
+        Some(min_funding_rate_bps) => min_funding_rate_bps,
+        None => return Ok(false),
+    };
+    let market_index = strategy_account.market_info.perp_market_index as usize;
+    let funding_rate = mango_util::get_funding_rate(
+        mango_program,
+        mango_group,
+        mango_cache,
+        mango_market,
+        mango_bids,
+        mango_asks,
+        market_index,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    // shorts receive positive funding
+    let earned_funding_rate = match strategy_account.mode {
+        BasisMode::Normal => funding_rate,
+        BasisMode::Reverse => -funding_rate,
+    };
+    if earned_funding_rate * I80F48::from_num(BPS_DENOMINATOR)
+        >= I80F48::from_num(min_funding_rate_bps)
+    {
+        return Ok(false);
+    }
+    let base_position = mango_util::get_perp_snapshot(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_index,
+    )
+    .map_err(ErrorCode::register_mango_error)?
+    .base_position;
+    let increases_position = match side {
+        mango::matching::Side::Bid => base_position >= 0,
+        mango::matching::Side::Ask => base_position <= 0,
+    };
+    if increases_position {
+        return Err(ErrorCode::FundingRateTooLow.into());
+    }
+    Ok(true)
+}
+
+/// Fails if the position after filling all orders on `side` plus `quantity` exceeds the limit
+fn check_perp_position_limit<'info>(
+    strategy_account: &StrategyAccount,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    side: mango::matching::Side,
+    quantity: i64,
+) -> Result<()> {
+    let max_perp_position = strategy_account.max_perp_position;
+    if max_perp_position == 0 {
+        return Ok(());
+    }
+    let position = mango_util::get_worst_case_perp_position(
+        mango_program,
+        mango_group,
+        mango_account,
+        strategy_account.market_info.perp_market_index as usize,
+        side,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    let position = match side {
+        mango::matching::Side::Bid => position + quantity,
+        mango::matching::Side::Ask => position - quantity,
+    };
+    if position.unsigned_abs() > max_perp_position {
+        return Err(ErrorCode::PerpPositionLimitReached.into());
+    }
+    Ok(())
+}
+
+/// Recomputes the NAV after the instruction, supply is reloaded after mints and burns.
+/// Not called where TVL and supply can't change: owner setters and timelocked changes,
+/// cancel_perp_orders and trigger order instructions (no balances move, trigger fills happen
+/// in Mango and are picked up by the next refresh), transfer_usdc (between the strategy's own
+/// Mango accounts, without borrowing), create_mango_account (empty account) and
+/// close_mango_account (supply is 0, close_strategy closes the NAV account)
+fn refresh_nav<'info>(
+    nav_account: &AccountLoader<'info, NavAccount>,
+    strategy_account: &Account<'info, StrategyAccount>,
+    strategy_token_mint: &Account<'info, Mint>,
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<()> {
+    let supply = Account::<Mint>::try_from(&strategy_token_mint.to_account_info())?.supply;
+    let tvl = calculate_strategy_tvl(
+        strategy_account,
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        remaining_accounts,
+    )?;
+    let market_info = &strategy_account.market_info;
+    let (spot, perp) = mango_util::get_hedge_positions(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_info,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    let price = mango_util::get_price(mango_cache, market_info.spot_token_index as usize)?;
+    let clock = Clock::get()?;
+    let mut nav = nav_account.load_mut()?;
+    nav.supply = supply;
+    // same virtual shares and assets as deposits and withdrawals
+    let total_supply = I80F48::from_num(nav.supply) + I80F48::from_num(VIRTUAL_SHARES);
+    let one = I80F48::from_num(10u64.pow(mango_strategy::STRATEGY_TOKEN_DECIMALS as u32));
+    nav.share_price = ((tvl + I80F48::from_num(VIRTUAL_ASSETS)) / total_supply * one)
+        .checked_to_num()
+        .unwrap_or(0);
+    nav.tvl = tvl.checked_to_num().unwrap_or(0);
+    nav.confidence = ((spot + perp).abs() * price / total_supply * one)
+        .checked_to_num()
+        .unwrap_or(u64::MAX);
+    nav.last_update_slot = clock.slot;
+    nav.last_update_ts = clock.unix_timestamp;
+    Ok(())
+}
+
+/// Fee on the value of shares received by transfer, from early_exit_fee_bps right after
+/// the withdrawer's last deposit down to 0 once min_holding_period passed.
+/// Without a deposit of their own (last_deposit_ts 0) the holding time is unknown, the full fee applies
+fn calculate_early_exit_fee(
+    strategy_account: &StrategyAccount,
+    last_deposit_ts: i64,
+    now: i64,
+    vault_token_amount: u64,
+) -> u64 {
+    let period = strategy_account.min_holding_period;
+    if period <= 0 {
+        return 0;
+    }
+    if last_deposit_ts == 0 {
+        return calculate_fee(vault_token_amount, strategy_account.early_exit_fee_bps);
+    }
+    let remaining = last_deposit_ts
+        .saturating_add(period)
+        .saturating_sub(now)
+        .min(period);
+    if remaining <= 0 {
+        return 0;
+    }
+    let fee_bps = strategy_account.early_exit_fee_bps as i64 * remaining / period;
+    calculate_fee(vault_token_amount, fee_bps as u16)
+}
+
+/// TVL and whitelist limits, the limits account is one of the remaining accounts
+fn check_deposit_limits(
+    strategy_account: &StrategyAccount,
+    remaining_accounts: &[AccountInfo<'_>],
+    owner: &Pubkey,
+    tvl: I80F48,
+    vault_token_amount: u64,
+) -> Result<()> {
+    if let Some(limits_account) = strategy_account.limits_account {
+        let limits_account_info = remaining_accounts
+            .iter()
+            .find(|acc| acc.key() == limits_account)
+            .ok_or(ErrorCode::InvalidLimitsAccount)?; // check limits account
+        let mut limits_account: LimitsAccount =
+            LimitsAccount::try_deserialize(&mut &limits_account_info.data.borrow_mut()[..])
+                .map_err(|_| ErrorCode::InvalidLimitsAccount)?;
+        if limits_account
+            .max_tvl
+            .map(|max_tvl_limit| (tvl + I80F48::from_num(vault_token_amount)) >= max_tvl_limit)
+            == Some(true)
+        {
+            return Err(ErrorCode::TvlLimitReached.into());
+        }
+        let limit = limits_account
+            .whitelist
+            .iter_mut()
+            .find(|x| x.key == *owner);
+        if let Some(WhitelistLimit { deposit, .. }) = limit {
+            *deposit += vault_token_amount;
+            if *deposit > limits_account.max_deposit {
+                return Err(ErrorCode::WhitelistLimitReached.into());
+            }
+            LimitsAccount::try_serialize(
+                &limits_account,
+                &mut &mut limits_account_info.data.borrow_mut()[..],
+            )?;
+        } else {
+            return Err(ErrorCode::NotInWhitelist.into());
+        }
+    }
+    Ok(())
+}
+
+/// Undoes the whitelist deposit counted by check_deposit_limits for a cancelled deposit
+fn release_deposit_limit(
+    strategy_account: &StrategyAccount,
+    remaining_accounts: &[AccountInfo<'_>],
+    owner: &Pubkey,
+    vault_token_amount: u64,
+) -> Result<()> {
+    if let Some(limits_account) = strategy_account.limits_account {
+        let limits_account_info = remaining_accounts
+            .iter()
+            .find(|acc| acc.key() == limits_account)
+            .ok_or(ErrorCode::InvalidLimitsAccount)?;
+        let mut limits_account: LimitsAccount =
+            LimitsAccount::try_deserialize(&mut &limits_account_info.data.borrow_mut()[..])
+                .map_err(|_| ErrorCode::InvalidLimitsAccount)?;
+        // the owner may have been removed from the whitelist since queueing
+        if let Some(WhitelistLimit { deposit, .. }) = limits_account
+            .whitelist
+            .iter_mut()
+            .find(|x| x.key == *owner)
+        {
+            *deposit = deposit.saturating_sub(vault_token_amount);
+            LimitsAccount::try_serialize(
+                &limits_account,
+                &mut &mut limits_account_info.data.borrow_mut()[..],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn check_no_timelock(strategy_account: &StrategyAccount) -> Result<()> {
+    if strategy_account.timelock_delay > 0 {
+        return Err(ErrorCode::TimelockRequired.into());
+    }
+    Ok(())
+}
+
+fn validate_change(change: &ParameterChange) -> Result<()> {
+    match change {
+        ParameterChange::Limits { whitelist, .. } => {
+            if whitelist.len() > LimitsAccount::WHITELIST_CAP {
+                return Err(ErrorCode::InvalidLimitsAccount.into());
+            }
+        }
+        ParameterChange::Fees {
+            deposit_fee_bps,
+            withdraw_fee_bps,
+        } => {
+            if *deposit_fee_bps > mango_strategy::MAX_FEE_BPS
+                || *withdraw_fee_bps > mango_strategy::MAX_FEE_BPS
+            {
+                return Err(ErrorCode::InvalidFee.into());
+            }
+        }
+        ParameterChange::MinHoldingPeriod {
+            min_holding_period,
+            early_exit_fee_bps,
+        } => {
+            if *min_holding_period < 0 {
+                return Err(ErrorCode::InvalidHoldingPeriod.into());
+            }
+            if *early_exit_fee_bps > mango_strategy::MAX_FEE_BPS {
+                return Err(ErrorCode::InvalidFee.into());
+            }
+        }
+        ParameterChange::OutflowLimit {
+            max_outflow_bps,
+            window,
+        } => {
+            if *max_outflow_bps as u64 > BPS_DENOMINATOR || *window <= 0 {
+                return Err(ErrorCode::InvalidOutflowLimit.into());
+            }
+        }
+        ParameterChange::HedgeConfig {
+            target_hedge_ratio_bps,
+            delta_tolerance_bps,
+        } => {
+            if *target_hedge_ratio_bps as u64 > BPS_DENOMINATOR
+                || *delta_tolerance_bps as u64 > BPS_DENOMINATOR
+            {
+                return Err(ErrorCode::InvalidHedgeConfig.into());
+            }
+        }
+        ParameterChange::MaxLeverage(max_leverage_bps) => {
+            if *max_leverage_bps > mango_strategy::MAX_LEVERAGE_BPS {
+                return Err(ErrorCode::InvalidLeverage.into());
+            }
+        }
+        ParameterChange::KeeperConfig(keeper_config) => {
+            if keeper_config.max_slippage_bps as u64 > BPS_DENOMINATOR
+                || keeper_config.bounty > mango_strategy::MAX_KEEPER_BOUNTY
+                || keeper_config.cooldown < 0
+            {
+                return Err(ErrorCode::InvalidKeeperConfig.into());
+            }
+        }
+        ParameterChange::TimelockDelay(timelock_delay) => {
+            if *timelock_delay < 0 || *timelock_delay > mango_strategy::MAX_TIMELOCK_DELAY {
+                return Err(ErrorCode::InvalidTimelockDelay.into());
+            }
+        }
+        ParameterChange::DepositEpoch(deposit_epoch) => {
+            if *deposit_epoch < 0 {
+                return Err(ErrorCode::InvalidDepositEpoch.into());
+            }
+        }
+        ParameterChange::DropLimits
+        | ParameterChange::MaxPerpPosition(_)
+        | ParameterChange::Mode(_)
+        | ParameterChange::MinFundingRate(_)
+        | ParameterChange::MaxBookDeviation(_) => {}
+    }
+    Ok(())
+}
+
+/// Limits changes need the limits account and are applied by execute_change
+fn apply_change(strategy_account: &mut StrategyAccount, change: &ParameterChange) -> Result<()> {
+    validate_change(change)?;
+    match change {
+        ParameterChange::Limits { .. } | ParameterChange::DropLimits => {
+            return Err(ErrorCode::InvalidLimitsAccount.into());
+        }
+        ParameterChange::Fees {
+            deposit_fee_bps,
+            withdraw_fee_bps,
+        } => {
+            strategy_account.deposit_fee_bps = *deposit_fee_bps;
+            strategy_account.withdraw_fee_bps = *withdraw_fee_bps;
+        }
+        ParameterChange::MinHoldingPeriod {
+            min_holding_period,
+            early_exit_fee_bps,
+        } => {
+            strategy_account.min_holding_period = *min_holding_period;
+            strategy_account.early_exit_fee_bps = *early_exit_fee_bps;
+        }
+        ParameterChange::OutflowLimit {
+            max_outflow_bps,
+            window,
+        } => {
+            strategy_account.outflow_limit = OutflowLimit {
+                max_outflow_bps: *max_outflow_bps,
+                window: *window,
+                ..Default::default()
+            };
+        }
+        ParameterChange::MaxPerpPosition(max_perp_position) => {
+            strategy_account.max_perp_position = *max_perp_position;
+        }
+        ParameterChange::HedgeConfig {
+            target_hedge_ratio_bps,
+            delta_tolerance_bps,
+        } => {
+            strategy_account.target_hedge_ratio_bps = *target_hedge_ratio_bps;
+            strategy_account.delta_tolerance_bps = *delta_tolerance_bps;
+        }
+        ParameterChange::Mode(mode) => strategy_account.mode = *mode,
+        ParameterChange::MinFundingRate(min_funding_rate_bps) => {
+            strategy_account.min_funding_rate_bps = *min_funding_rate_bps;
+        }
+        ParameterChange::MaxBookDeviation(max_book_deviation_bps) => {
+            strategy_account.max_book_deviation_bps = *max_book_deviation_bps;
+        }
+        ParameterChange::MaxLeverage(max_leverage_bps) => {
+            strategy_account.max_leverage_bps = *max_leverage_bps;
+        }
+        ParameterChange::KeeperConfig(keeper_config) => {
+            strategy_account.keeper_config = keeper_config.clone();
+        }
+        ParameterChange::TimelockDelay(timelock_delay) => {
+            strategy_account.timelock_delay = *timelock_delay;
+        }
+        ParameterChange::DepositEpoch(deposit_epoch) => {
+            strategy_account.deposit_epoch = *deposit_epoch;
+        }
+    }
+    Ok(())
+}
+
+/// The strategy limits account, first of the remaining accounts
+fn load_limits_account<'info>(
+    strategy_account: &StrategyAccount,
+    remaining_accounts: &[AccountInfo<'info>],
+) -> Result<Account<'info, LimitsAccount>> {
+    let limits_account_info = remaining_accounts
+        .first()
+        .ok_or(ErrorCode::InvalidLimitsAccount)?;
+    if strategy_account.limits_account != Some(limits_account_info.key()) {
+        return Err(ErrorCode::InvalidLimitsAccount.into());
+    }
+    Account::try_from(limits_account_info)
+}
+
+/// New limits account paid by the owner, first of the remaining accounts (signer)
+fn create_limits_account<'info>(
+    owner: &AccountInfo<'info>,
+    system_program: &Program<'info, System>,
+    remaining_accounts: &[AccountInfo<'info>],
+    program_id: &Pubkey,
+) -> Result<Account<'info, LimitsAccount>> {
+    let limits_account_info = remaining_accounts
+        .first()
+        .ok_or(ErrorCode::InvalidLimitsAccount)?;
+    solana_program::program::invoke(
+        &solana_program::system_instruction::create_account(
+            &owner.key(),
+            &limits_account_info.key(),
+            Rent::get()?.minimum_balance(LimitsAccount::LEN),
+            LimitsAccount::LEN as u64,
+            program_id,
+        ),
+        &[
+            owner.to_owned(),
+            limits_account_info.to_owned(),
+            system_program.to_account_info(),
+        ],
+    )?;
+    // zeroed data, the discriminator is written by exit
+    Account::try_from_unchecked(limits_account_info)
+}
+
+#[error_code]
+pub enum ErrorCode {
+    InvalidLimitsAccount,
+    TvlLimitReached,
+    WhitelistLimitReached,
+    NotInWhitelist,
+    SlippageExceeded,
+    InvalidFee,
+    HoldingPeriodNotElapsed,
+    InvalidHoldingPeriod,
+    OutflowLimitReached,
+    InvalidOutflowLimit,
+    StrategyNotEmpty,
+    InvalidSlippage,
+    PerpPositionLimitReached,
+    InvalidAdvancedOrdersAccount,
+    DeltaOutOfBand,
+    InvalidHedgeConfig,
+    InvalidModeDirection,
+    FundingRateTooLow,
+    LeverageTooHigh,
+    InvalidLeverage,
+    InvalidKeeperConfig,
+    DeleverageNotAllowed,
+    RebalanceNotAllowed,
+    InvalidMangoAccount,
+    TimelockRequired,
+    TimelockNotElapsed,
+    InvalidTimelockDelay,
+    NotInRegistry,
+    BatchedDepositsOnly,
+    BatchedDepositsDisabled,
+    InvalidDepositEpoch,
+    DepositEpochNotElapsed,
+    InvalidPendingDeposit,
+    NoPendingDeposits,
+    InvalidStrategyAccountVersion,
+    InvalidOwner,
+    InvalidTvl,
+    MathOverflow,
+    MangoAccountNotClosed,
+    CircuitBreakerTripped,
+    CircuitBreakerNotTripped,
+    KeeperCooldown,
+    PendingDepositsNotProcessed,
+}
+
+#[event]
+pub struct DepositEvent {
+    pub strategy_account: Pubkey,
+    pub owner: Pubkey,
+    /// including fee
+    pub vault_token_amount: u64,
+    pub strategy_token_amount: u64,
+    pub fee: u64,
+}
+
+#[event]
+pub struct DepositQueuedEvent {
+    pub strategy_account: Pubkey,
+    pub owner: Pubkey,
+    pub vault_token_amount: u64,
+    pub epoch: u64,
+}
+
+#[event]
+pub struct DepositCancelledEvent {
+    pub strategy_account: Pubkey,
+    pub owner: Pubkey,
+    pub vault_token_amount: u64,
+}
+
+/// Per depositor DepositEvents are emitted as well
+#[event]
+pub struct DepositsProcessedEvent {
+    pub strategy_account: Pubkey,
+    pub epoch: u64,
+    pub vault_token_amount: u64,
+    pub strategy_token_amount: u64,
+    pub tvl_before: u64,
+    pub tvl_after: u64,
+}
+
+#[event]
+pub struct HarvestMngoEvent {
+    pub strategy_account: Pubkey,
+    /// USDC received for sold MNGO
+    pub usdc_amount: u64,
+}
+
+/// Share price snapshot appended by the snapshot crank, APYs are I80F48 bits
+#[event]
+pub struct SharePriceEvent {
+    pub strategy_account: Pubkey,
+    pub timestamp: i64,
+    pub share_price: i128,
+    pub tvl: u64,
+    pub apy_7d: Option<i128>,
+    pub apy_30d: Option<i128>,
+}
+
+/// Native base, delta = spot * target ratio + perp
+#[event]
+pub struct DeltaEvent {
+    pub strategy_account: Pubkey,
+    pub spot: i64,
+    pub perp: i64,
+    pub delta: i64,
+    pub band: i64,
+    pub out_of_band: bool,
+}
+
+/// Health ratios in bps, bounty in strategy tokens
+#[event]
+pub struct DeleverageEvent {
+    pub strategy_account: Pubkey,
+    pub health_ratio_bps: i64,
+    pub health_ratio_bps_after: i64,
+    pub bounty: u64,
+}
+
+/// Drift in bps, bounty in strategy tokens
+#[event]
+pub struct RebalancePublicEvent {
+    pub strategy_account: Pubkey,
+    pub drift_bps: i64,
+    pub drift_bps_after: i64,
+    pub bounty: u64,
+}
+
+/// Emitted by trip_circuit_breaker, prices are I80F48 bits
+#[event]
+pub struct CircuitBreakerTripped {
+    pub strategy_account: Pubkey,
+    /// None if a book side is empty
+    pub book_mid: Option<i128>,
+    pub oracle_price: i128,
+    pub max_deviation_bps: u16,
+}
+
+/// Perp trade by adjust_position_perp, native quote
+#[event]
+pub struct RebalanceEvent {
+    pub strategy_account: Pubkey,
+    /// base lots
+    pub base_change: i64,
+    /// accrued since the previous trade
+    pub funding: i64,
+    pub estimated_fee: u64,
+    /// against the oracle price, fees included
+    pub slippage: i64,
+}
+
+/// Cumulative, native quote
+#[event]
+pub struct StatsEvent {
+    pub strategy_account: Pubkey,
+    pub funding_earned: i64,
+    pub estimated_fees: u64,
+    pub slippage: i64,
+}
+
+#[event]
+pub struct ChangeProposedEvent {
+    pub strategy_account: Pubkey,
+    pub change: ParameterChange,
+    pub executable_at: i64,
+}
+
+#[event]
+pub struct ChangeExecutedEvent {
+    pub strategy_account: Pubkey,
+    pub change: ParameterChange,
+}
+
+#[event]
+pub struct ChangeCancelledEvent {
+    pub strategy_account: Pubkey,
+    pub change: ParameterChange,
+}
+
+#[event]
+pub struct TriggerOrderEvent {
+    pub strategy_account: Pubkey,
+    pub order_index: u8,
+    /// > 0: bid, < 0: ask
+    pub amount: i64,
+    pub price: i64,
+    pub trigger_condition: TriggerCondition,
+    /// I80F48 bits
+    pub trigger_price: i128,
+    pub client_order_id: u64,
+}
+
+#[event]
+pub struct WithdrawEvent {
+    pub strategy_account: Pubkey,
+    pub owner: Pubkey,
+    /// excluding fee
+    pub vault_token_amount: u64,
+    pub strategy_token_amount: u64,
+    pub fee: u64,
+}
+
+/// Emitted when a Mango call fails, carries the source location dropped by the error code
+#[event]
+pub struct MangoErrorEvent {
+    /// `MangoErrorCode` as u32
+    pub mango_error_code: u32,
+    /// `SourceFileId` as u8
+    pub source_file_id: u8,
+    pub line: u32,
+}
 
 impl ErrorCode {
-    pub fn register_mango_error(e: MangoError) -> Self {
+    /// Mango error codes are returned as `MANGO_ERROR_OFFSET + code`, see `mango_util::decode_mango_error`
+    pub fn register_mango_error(e: MangoError) -> anchor_lang::error::Error {
         solana_program::log::sol_log(&format!("Mango error: {}", e));
-        ErrorCode::MangoError
+        match e {
+            MangoError::ProgramError(pe) => pe.into(),
+            MangoError::MangoErrorCode {
+                mango_error_code,
+                line,
+                source_file_id,
+            } => {
+                emit!(MangoErrorEvent {
+                    mango_error_code: mango_error_code.into(),
+                    source_file_id: source_file_id as u8,
+                    line,
+                });
+                ProgramError::Custom(mango_util::encode_mango_error(mango_error_code)).into()
+            }
+        }
     }
 }
@@ -5,16 +5,23 @@ use anchor_lang::{
     Key, ToAccountMetas,
 };
 use anchor_spl::token::Mint;
-use az::Cast;
+use az::{Cast, CheckedCast};
 use fixed::types::I80F48;
 use mango::{
-    error::MangoError,
+    error::{MangoError, MangoErrorCode},
     instruction::{
-        consume_events, create_mango_account, create_spot_open_orders, deposit, place_perp_order,
-        withdraw, MangoInstruction,
+        add_perp_trigger_order, cache_perp_markets, cache_prices, cancel_all_perp_orders,
+        cancel_perp_order_by_client_id, close_advanced_orders, close_mango_account,
+        close_spot_open_orders, consume_events, create_mango_account, create_spot_open_orders,
+        deposit, init_advanced_orders, place_perp_order, remove_advanced_order, set_delegate,
+        update_root_bank, withdraw, MangoInstruction,
+    },
+    matching::{Book, OrderType, Side as MangoSide},
+    state::{
+        load_market_state, AdvancedOrderType, AdvancedOrders, HealthCache, HealthType,
+        MangoAccount, MangoCache, MangoGroup, PerpMarket, PerpTriggerOrder, TriggerCondition,
+        UserActiveAssets, MAX_PAIRS,
     },
-    matching::{OrderType, Side as MangoSide},
-    state::{MangoAccount, MangoCache, MangoGroup},
 };
 use mango_common::Loadable;
 use serum_dex::{
@@ -22,13 +29,83 @@ use serum_dex::{
     matching::Side as SerumSide,
 };
 use solana_program::{
-    instruction::Instruction,
+    instruction::{AccountMeta, Instruction},
     program::{invoke, invoke_signed},
+    program_error::ProgramError,
 };
 
-use crate::MarketInfo;
+use crate::{ErrorCode, MarketInfo, StrategyStats};
+
+pub const USDC_TOKEN_INDEX: usize = 15;
+
+/// Strategy errors start at 6000 (anchor), mapped Mango errors are reserved 7000..8000
+pub const MANGO_ERROR_OFFSET: u32 = 7000;
+const MANGO_ERROR_RANGE: u32 = 1000;
+
+const MANGO_ERROR_CODES: [MangoErrorCode; 41] = [
+    MangoErrorCode::InvalidCache,
+    MangoErrorCode::InvalidOwner,
+    MangoErrorCode::InvalidGroupOwner,
+    MangoErrorCode::InvalidSignerKey,
+    MangoErrorCode::InvalidAdminKey,
+    MangoErrorCode::InvalidVault,
+    MangoErrorCode::MathError,
+    MangoErrorCode::InsufficientFunds,
+    MangoErrorCode::InvalidToken,
+    MangoErrorCode::InvalidMarket,
+    MangoErrorCode::InvalidProgramId,
+    MangoErrorCode::GroupNotRentExempt,
+    MangoErrorCode::OutOfSpace,
+    MangoErrorCode::TooManyOpenOrders,
+    MangoErrorCode::AccountNotRentExempt,
+    MangoErrorCode::ClientIdNotFound,
+    MangoErrorCode::InvalidNodeBank,
+    MangoErrorCode::InvalidRootBank,
+    MangoErrorCode::MarginBasketFull,
+    MangoErrorCode::NotLiquidatable,
+    MangoErrorCode::Unimplemented,
+    MangoErrorCode::PostOnly,
+    MangoErrorCode::Bankrupt,
+    MangoErrorCode::InsufficientHealth,
+    MangoErrorCode::InvalidParam,
+    MangoErrorCode::InvalidAccount,
+    MangoErrorCode::InvalidAccountState,
+    MangoErrorCode::SignerNecessary,
+    MangoErrorCode::InsufficientLiquidity,
+    MangoErrorCode::InvalidOrderId,
+    MangoErrorCode::InvalidOpenOrdersAccount,
+    MangoErrorCode::BeingLiquidated,
+    MangoErrorCode::InvalidRootBankCache,
+    MangoErrorCode::InvalidPriceCache,
+    MangoErrorCode::InvalidPerpMarketCache,
+    MangoErrorCode::TriggerConditionFalse,
+    MangoErrorCode::InvalidSeeds,
+    MangoErrorCode::InvalidOracleType,
+    MangoErrorCode::InvalidOraclePrice,
+    MangoErrorCode::MaxAccountsReached,
+    MangoErrorCode::Default,
+];
 
-const USDC_TOKEN_INDEX: usize = 15;
+/// MangoErrorCode::Default (u32::MAX) is clamped to the last code of the range
+pub fn encode_mango_error(mango_error_code: MangoErrorCode) -> u32 {
+    let code: u32 = mango_error_code.into();
+    MANGO_ERROR_OFFSET + code.min(MANGO_ERROR_RANGE - 1)
+}
+
+/// Off-chain helper: maps a custom program error code back to MangoErrorCode
+pub fn decode_mango_error(error_code: u32) -> Option<MangoErrorCode> {
+    if !(MANGO_ERROR_OFFSET..MANGO_ERROR_OFFSET + MANGO_ERROR_RANGE).contains(&error_code) {
+        return None;
+    }
+    let code = error_code - MANGO_ERROR_OFFSET;
+    Some(
+        MANGO_ERROR_CODES
+            .iter()
+            .copied()
+            .find(|&x| encode_mango_error(x) - MANGO_ERROR_OFFSET == code)
+            .unwrap_or(MangoErrorCode::Default),
+    )
+}
 
 pub fn create_account<'info>(
     mango_program: &AccountInfo<'info>,
@@ -107,6 +184,281 @@ pub fn create_open_orders<'info>(
     Ok(())
 }
 
+pub fn close_account<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let instruction = close_mango_account(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &owner.key(),
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            owner.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+pub fn close_open_orders<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    serum_dex: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+    spot_market: &AccountInfo<'info>,
+    mango_signer: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let instruction = close_spot_open_orders(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &owner.key(),
+        &serum_dex.key(),
+        &spot_open_orders.key(),
+        &spot_market.key(),
+        &mango_signer.key(),
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            owner.to_owned(),
+            serum_dex.to_owned(),
+            spot_open_orders.to_owned(),
+            spot_market.to_owned(),
+            mango_signer.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+pub fn set_account_delegate<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    delegate: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let instruction = set_delegate(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &owner.key(),
+        &delegate.key(),
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            owner.to_owned(),
+            delegate.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+/// `payer` pays rent of the advanced orders account, signed by `seeds`
+pub fn create_advanced_orders<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    advanced_orders: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let instruction = init_advanced_orders(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &payer.key(),
+        &advanced_orders.key(),
+        &system_program.key(),
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            payer.to_owned(),
+            advanced_orders.to_owned(),
+            system_program.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+/// Reduce only immediate-or-cancel order at `price` (quote lots per base lot) when `trigger_price` is crossed,
+/// `payer` pays the execution fee, signed by `seeds`
+pub fn add_trigger_order<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    advanced_orders: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    side: MangoSide,
+    trigger_condition: TriggerCondition,
+    client_order_id: u64,
+    price: i64,
+    quantity: i64,
+    trigger_price: I80F48,
+) -> Result<()> {
+    let mut instruction = add_perp_trigger_order(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &payer.key(),
+        &advanced_orders.key(),
+        &mango_cache.key(),
+        &mango_market.key(),
+        &system_program.key(),
+        OrderType::ImmediateOrCancel,
+        side,
+        trigger_condition,
+        true,
+        client_order_id,
+        price,
+        quantity,
+        trigger_price,
+    )?;
+    instruction
+        .accounts
+        .push(AccountMeta::new_readonly(spot_open_orders.key(), false));
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            payer.to_owned(),
+            advanced_orders.to_owned(),
+            mango_cache.to_owned(),
+            mango_market.to_owned(),
+            system_program.to_owned(),
+            spot_open_orders.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+/// Execution fee is refunded to `owner`
+pub fn remove_trigger_order<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    advanced_orders: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    order_index: u8,
+) -> Result<()> {
+    let instruction = remove_advanced_order(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &owner.key(),
+        &advanced_orders.key(),
+        &system_program.key(),
+        order_index,
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            owner.to_owned(),
+            advanced_orders.to_owned(),
+            system_program.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+pub fn close_trigger_orders<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    owner: &AccountInfo<'info>,
+    advanced_orders: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let instruction = close_advanced_orders(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_account.key(),
+        &advanced_orders.key(),
+        &owner.key(),
+    )?;
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_account.to_owned(),
+            owner.to_owned(),
+            advanced_orders.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+/// Active perp trigger orders with their index in the advanced orders account
+pub fn get_trigger_orders<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    advanced_orders: &AccountInfo<'info>,
+) -> std::result::Result<Vec<(u8, PerpTriggerOrder)>, MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    if mango_account.advanced_orders_key != advanced_orders.key()
+        || advanced_orders.owner != &mango_program.key()
+    {
+        return Err(MangoError::ProgramError(ProgramError::InvalidArgument));
+    }
+    let advanced_orders = AdvancedOrders::load(advanced_orders)?;
+    Ok(advanced_orders
+        .orders
+        .iter()
+        .enumerate()
+        .filter(|(_, order)| {
+            order.is_active && order.advanced_order_type == AdvancedOrderType::PerpTrigger
+        })
+        .map(|(i, order)| (i as u8, *bytemuck::cast_ref::<_, PerpTriggerOrder>(order)))
+        .collect())
+}
+
 pub fn deposit_tokens<'info>(
     mango_program: &AccountInfo<'info>,
     mango_group: &AccountInfo<'info>,
@@ -153,6 +505,67 @@ pub fn deposit_tokens<'info>(
     Ok(())
 }
 
+/// Refreshes the oracle price, perp market and root bank caches used for the TVL
+pub fn refresh_cache<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    oracle: &AccountInfo<'info>,
+    perp_market: &AccountInfo<'info>,
+    root_banks: &[(&AccountInfo<'info>, &AccountInfo<'info>)],
+) -> Result<()> {
+    let instruction = cache_prices(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_cache.key(),
+        &[oracle.key()],
+    )?;
+    invoke(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_cache.to_owned(),
+            oracle.to_owned(),
+        ],
+    )?;
+    let instruction = cache_perp_markets(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_cache.key(),
+        &[perp_market.key()],
+    )?;
+    invoke(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
+            mango_cache.to_owned(),
+            perp_market.to_owned(),
+        ],
+    )?;
+    for (root_bank, node_bank) in root_banks {
+        let instruction = update_root_bank(
+            &mango_program.key(),
+            &mango_group.key(),
+            &mango_cache.key(),
+            &root_bank.key(),
+            &[node_bank.key()],
+        )?;
+        invoke(
+            &instruction,
+            &[
+                mango_program.to_owned(),
+                mango_group.to_owned(),
+                mango_cache.to_owned(),
+                root_bank.to_owned(),
+                node_bank.to_owned(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
 pub fn withdraw_tokens<'info>(
     mango_program: &AccountInfo<'info>,
     mango_group: &AccountInfo<'info>,
@@ -235,68 +648,401 @@ pub fn adjust_position_perp<'info>(
     market_index: usize,
     reduce_only: bool,
 ) -> Result<()> {
-    let mut mango_spot_open_orders = ["11111111111111111111111111111111".parse().unwrap(); 15];
-    mango_spot_open_orders[market_index] = spot_open_orders.key();
-    let instruction = place_perp_order(
-        &mango_program.key(),
-        &mango_group.key(),
-        &mango_account.key(),
-        &authority.key(),
-        &mango_cache.key(),
-        &mango_market.key(),
-        &mango_bids.key(),
-        &mango_asks.key(),
-        &mango_event_queue.key(),
-        &mango_spot_open_orders,
+    place_perp_ioc_order(
+        mango_program,
+        mango_group,
+        mango_account,
+        authority,
+        mango_cache,
+        mango_market,
+        mango_bids,
+        mango_asks,
+        mango_event_queue,
+        spot_open_orders,
+        seeds,
         side,
         match side {
             MangoSide::Bid => i64::MAX,
             MangoSide::Ask => 1,
         },
+        amount_base,
+        market_index,
+        reduce_only,
+    )
+}
+
+/// Immediate-or-cancel perp order at `price` (quote lots per base lot), events are consumed
+/// so the position is updated
+pub fn place_perp_ioc_order<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    mango_bids: &AccountInfo<'info>,
+    mango_asks: &AccountInfo<'info>,
+    mango_event_queue: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    side: MangoSide,
+    price: i64,
+    amount_base: i64,
+    market_index: usize,
+    reduce_only: bool,
+) -> Result<()> {
+    place_perp_order_signed(
+        mango_program,
+        mango_group,
+        mango_account,
+        authority,
+        mango_cache,
+        mango_market,
+        mango_bids,
+        mango_asks,
+        mango_event_queue,
+        spot_open_orders,
+        seeds,
+        side,
+        price,
         amount_base.cast(),
         1,
         OrderType::ImmediateOrCancel,
+        market_index,
         reduce_only,
     )?;
-    invoke_signed(
+    let instruction = consume_events(
+        &mango_program.key(),
+        &mango_group.key(),
+        &mango_cache.key(),
+        &mango_market.key(),
+        &mango_event_queue.key(),
+        &mut [mango_account.key()],
+        64,
+    )?;
+    invoke(
         &instruction,
         &[
             mango_program.to_owned(),
             mango_group.to_owned(),
-            mango_account.to_owned(),
-            authority.to_owned(),
             mango_cache.to_owned(),
             mango_market.to_owned(),
-            mango_bids.to_owned(),
-            mango_asks.to_owned(),
             mango_event_queue.to_owned(),
-            spot_open_orders.to_owned(),
+            mango_account.to_owned(),
         ],
-        seeds,
     )?;
-    let instruction = consume_events(
+    Ok(())
+}
+
+/// `price` in quote lots per base lot, `quantity` in base lots
+pub fn place_perp_order_signed<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    mango_bids: &AccountInfo<'info>,
+    mango_asks: &AccountInfo<'info>,
+    mango_event_queue: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    side: MangoSide,
+    price: i64,
+    quantity: i64,
+    client_order_id: u64,
+    order_type: OrderType,
+    market_index: usize,
+    reduce_only: bool,
+) -> Result<()> {
+    let mut mango_spot_open_orders = ["11111111111111111111111111111111".parse().unwrap(); 15];
+    mango_spot_open_orders[market_index] = spot_open_orders.key();
+    let instruction = place_perp_order(
         &mango_program.key(),
         &mango_group.key(),
+        &mango_account.key(),
+        &authority.key(),
         &mango_cache.key(),
         &mango_market.key(),
+        &mango_bids.key(),
+        &mango_asks.key(),
         &mango_event_queue.key(),
-        &mut [mango_account.key()],
-        64,
+        &mango_spot_open_orders,
+        side,
+        price,
+        quantity,
+        client_order_id,
+        order_type,
+        reduce_only,
     )?;
-    invoke(
+    invoke_signed(
         &instruction,
         &[
             mango_program.to_owned(),
             mango_group.to_owned(),
+            mango_account.to_owned(),
+            authority.to_owned(),
             mango_cache.to_owned(),
             mango_market.to_owned(),
+            mango_bids.to_owned(),
+            mango_asks.to_owned(),
             mango_event_queue.to_owned(),
+            spot_open_orders.to_owned(),
+        ],
+        seeds,
+    )?;
+    Ok(())
+}
+
+/// `client_order_id` None cancels all orders
+pub fn cancel_perp_orders<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    mango_market: &AccountInfo<'info>,
+    mango_bids: &AccountInfo<'info>,
+    mango_asks: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    client_order_id: Option<u64>,
+) -> Result<()> {
+    let instruction = match client_order_id {
+        Some(client_order_id) => cancel_perp_order_by_client_id(
+            &mango_program.key(),
+            &mango_group.key(),
+            &mango_account.key(),
+            &authority.key(),
+            &mango_market.key(),
+            &mango_bids.key(),
+            &mango_asks.key(),
+            client_order_id,
+            false,
+        )?,
+        None => cancel_all_perp_orders(
+            &mango_program.key(),
+            &mango_group.key(),
+            &mango_account.key(),
+            &authority.key(),
+            &mango_market.key(),
+            &mango_bids.key(),
+            &mango_asks.key(),
+            u8::MAX,
+        )?,
+    };
+    invoke_signed(
+        &instruction,
+        &[
+            mango_program.to_owned(),
+            mango_group.to_owned(),
             mango_account.to_owned(),
+            authority.to_owned(),
+            mango_market.to_owned(),
+            mango_bids.to_owned(),
+            mango_asks.to_owned(),
         ],
+        seeds,
     )?;
     Ok(())
 }
 
+/// Perp account state used for stats
+pub struct PerpSnapshot {
+    /// base lots, including unprocessed taker trades
+    pub base_position: i64,
+    /// native quote including unsettled funding and unprocessed taker trades
+    pub quote_position: I80F48,
+    pub long_funding: I80F48,
+    pub short_funding: I80F48,
+    pub price: I80F48,
+    pub base_lot_size: i64,
+    pub taker_fee: I80F48,
+}
+
+pub fn get_perp_snapshot<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    market_index: usize,
+) -> std::result::Result<PerpSnapshot, MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let perp_account = &mango_account.perp_accounts[market_index];
+    let perp_market_cache = &mango_cache_data.perp_market_cache[market_index];
+    let perp_market_info = &mango_group_data.perp_markets[market_index];
+    Ok(PerpSnapshot {
+        base_position: perp_account.base_position + perp_account.taker_base,
+        quote_position: perp_account.get_quote_position(perp_market_cache)
+            + I80F48::from_num(perp_account.taker_quote * perp_market_info.quote_lot_size),
+        long_funding: perp_market_cache.long_funding,
+        short_funding: perp_market_cache.short_funding,
+        price: mango_cache_data.get_price(market_index),
+        base_lot_size: perp_market_info.base_lot_size,
+        taker_fee: perp_market_info.taker_fee,
+    })
+}
+
+/// Funding accrued since the last update by the position recorded in stats, native quote
+pub fn get_pending_funding(stats: &StrategyStats, snapshot: &PerpSnapshot) -> Result<i64> {
+    let funding = if stats.base_position > 0 {
+        snapshot.long_funding - I80F48::from_bits(stats.long_funding_index)
+    } else {
+        snapshot.short_funding - I80F48::from_bits(stats.short_funding_index)
+    };
+    // same sign as PerpAccount::settle_funding
+    let funding: i64 = funding
+        .checked_mul(I80F48::from_num(stats.base_position))
+        .and_then(|funding| funding.checked_neg())
+        .and_then(|funding| funding.checked_to_num())
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(funding)
+}
+
+/// Accrues funding of the recorded position, records the taker trade between two snapshots
+/// and resyncs the position with Mango, returns (funding, estimated_fee, slippage).
+/// Resting and trigger orders fill between instructions, their fills only move the position.
+/// Mango doesn't record fees per account, the fee is estimated from the traded quote and the
+/// taker fee. Slippage is the trade value against the oracle price, fees included
+pub fn record_perp_stats(
+    stats: &mut StrategyStats,
+    before: &PerpSnapshot,
+    after: &PerpSnapshot,
+) -> Result<(i64, u64, i64)> {
+    let funding = get_pending_funding(stats, before)?;
+    let base_change = after
+        .base_position
+        .checked_sub(before.base_position)
+        .and_then(|change| change.checked_mul(after.base_lot_size))
+        .ok_or(ErrorCode::MathOverflow)?;
+    let quote_change = after
+        .quote_position
+        .checked_sub(before.quote_position)
+        .ok_or(ErrorCode::MathOverflow)?;
+    let estimated_fee: u64 = quote_change
+        .abs()
+        .checked_mul(after.taker_fee)
+        .and_then(|estimated_fee| estimated_fee.checked_to_num())
+        .ok_or(ErrorCode::MathOverflow)?;
+    let slippage: i64 = I80F48::from_num(base_change)
+        .checked_mul(after.price)
+        .and_then(|value| value.checked_add(quote_change))
+        .and_then(|slippage| slippage.checked_to_num())
+        .ok_or(ErrorCode::MathOverflow)?;
+    stats.funding_earned = stats
+        .funding_earned
+        .checked_add(funding)
+        .ok_or(ErrorCode::MathOverflow)?;
+    stats.estimated_fees = stats
+        .estimated_fees
+        .checked_add(estimated_fee)
+        .ok_or(ErrorCode::MathOverflow)?;
+    stats.slippage = stats
+        .slippage
+        .checked_add(slippage)
+        .ok_or(ErrorCode::MathOverflow)?;
+    stats.long_funding_index = after.long_funding.to_bits();
+    stats.short_funding_index = after.short_funding.to_bits();
+    stats.base_position = after.base_position;
+    Ok((funding, estimated_fee, slippage))
+}
+
+fn check_perp_market(
+    mango_group: &MangoGroup,
+    perp_market: &AccountInfo,
+    market_index: usize,
+) -> std::result::Result<(), MangoError> {
+    if mango_group.find_perp_market_index(perp_market.key) != Some(market_index) {
+        return Err(ProgramError::InvalidArgument.into());
+    }
+    Ok(())
+}
+
+/// Book mid from the best bid and ask (None if a side is empty) and oracle price, native
+pub fn get_book_mid_and_oracle_price<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    perp_market: &AccountInfo<'info>,
+    bids: &AccountInfo<'info>,
+    asks: &AccountInfo<'info>,
+    market_index: usize,
+) -> std::result::Result<(Option<I80F48>, I80F48), MangoError> {
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    check_perp_market(&mango_group_data, perp_market, market_index)?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let perp_market_data =
+        PerpMarket::load_checked(perp_market, &mango_program.key(), &mango_group.key())?;
+    let book = Book::load_checked(&mango_program.key(), bids, asks, &perp_market_data)?;
+    let book_mid = match (book.get_best_bid_price(), book.get_best_ask_price()) {
+        (Some(bid), Some(ask)) => Some(
+            (perp_market_data.lot_to_native_price(bid) + perp_market_data.lot_to_native_price(ask))
+                / I80F48::from_num(2),
+        ),
+        _ => None,
+    };
+    Ok((book_mid, mango_cache_data.get_price(market_index)))
+}
+
+/// Book depth in base lots used for funding, same as PerpMarket::update_funding
+const FUNDING_IMPACT_QUANTITY: i64 = 100;
+
+/// Annualised funding rate paid by longs (received by shorts if positive),
+/// book mid at impact quantity vs oracle price as in PerpMarket::update_funding
+pub fn get_funding_rate<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    perp_market: &AccountInfo<'info>,
+    bids: &AccountInfo<'info>,
+    asks: &AccountInfo<'info>,
+    market_index: usize,
+) -> std::result::Result<I80F48, MangoError> {
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    check_perp_market(&mango_group_data, perp_market, market_index)?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let perp_market_data =
+        PerpMarket::load_checked(perp_market, &mango_program.key(), &mango_group.key())?;
+    let book = Book::load_checked(&mango_program.key(), bids, asks, &perp_market_data)?;
+    let max_funding = I80F48::from_num(5) / I80F48::from_num(100);
+    let daily_funding = match (
+        book.get_impact_price(MangoSide::Bid, FUNDING_IMPACT_QUANTITY),
+        book.get_impact_price(MangoSide::Ask, FUNDING_IMPACT_QUANTITY),
+    ) {
+        (Some(bid), Some(ask)) => {
+            let book_price = perp_market_data.lot_to_native_price((bid + ask) / 2);
+            (book_price / mango_cache_data.get_price(market_index) - I80F48::ONE)
+                .clamp(-max_funding, max_funding)
+        }
+        (Some(_bid), None) => max_funding,
+        (None, Some(_ask)) => -max_funding,
+        (None, None) => I80F48::ZERO,
+    };
+    Ok(daily_funding * I80F48::from_num(365))
+}
+
+/// Perp position in base lots if all resting orders on `side` were filled
+pub fn get_worst_case_perp_position<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    market_index: usize,
+    side: MangoSide,
+) -> std::result::Result<i64, MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let perp_account = &mango_account.perp_accounts[market_index];
+    let position = perp_account.base_position + perp_account.taker_base;
+    Ok(match side {
+        MangoSide::Bid => position + perp_account.bids_quantity,
+        MangoSide::Ask => position - perp_account.asks_quantity,
+    })
+}
+
 pub fn adjust_position_spot<'info>(
     mango_program: &AccountInfo<'info>,
     mango_group: &AccountInfo<'info>,
@@ -331,10 +1077,76 @@ pub fn adjust_position_spot<'info>(
         SerumSide::Bid => 100000000000 * market_lot_size,
         SerumSide::Ask => 1 * market_lot_size,
     };
-    let limit_price = NonZeroU64::new(price).unwrap();
-    let max_base_quantity = NonZeroU64::new(amount).unwrap();
-    let max_quote_quantity = NonZeroU64::new(amount * price).unwrap();
-    let accounts = vec![
+    place_spot_order(
+        mango_program,
+        mango_group,
+        mango_account,
+        authority,
+        mango_cache,
+        mango_signer,
+        serum_dex,
+        spot_market,
+        spot_bids,
+        spot_asks,
+        spot_request_queue,
+        spot_event_queue,
+        spot_base,
+        spot_quote,
+        spot_base_root_bank,
+        spot_base_node_bank,
+        spot_base_vault,
+        spot_quote_root_bank,
+        spot_quote_node_bank,
+        spot_quote_vault,
+        serum_dex_signer,
+        &[spot_open_orders.to_owned()],
+        srm_vault,
+        token_program,
+        seeds,
+        side,
+        price,
+        amount,
+        amount * price,
+    )
+}
+
+/// Immediate-or-cancel order through PlaceSpotOrder2,
+/// `open_orders` - open orders of the traded market and of all markets in the margin basket
+pub fn place_spot_order<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_signer: &AccountInfo<'info>,
+    serum_dex: &AccountInfo<'info>,
+    spot_market: &AccountInfo<'info>,
+    spot_bids: &AccountInfo<'info>,
+    spot_asks: &AccountInfo<'info>,
+    spot_request_queue: &AccountInfo<'info>,
+    spot_event_queue: &AccountInfo<'info>,
+    spot_base: &AccountInfo<'info>,
+    spot_quote: &AccountInfo<'info>,
+    spot_base_root_bank: &AccountInfo<'info>,
+    spot_base_node_bank: &AccountInfo<'info>,
+    spot_base_vault: &AccountInfo<'info>,
+    spot_quote_root_bank: &AccountInfo<'info>,
+    spot_quote_node_bank: &AccountInfo<'info>,
+    spot_quote_vault: &AccountInfo<'info>,
+    serum_dex_signer: &AccountInfo<'info>,
+    open_orders: &[AccountInfo<'info>],
+    srm_vault: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+    side: SerumSide,
+    limit_price: u64,
+    max_base_quantity: u64,
+    max_quote_quantity: u64,
+) -> Result<()> {
+    let limit_price = NonZeroU64::new(limit_price).unwrap();
+    let max_base_quantity = NonZeroU64::new(max_base_quantity).unwrap();
+    let max_quote_quantity = NonZeroU64::new(max_quote_quantity).unwrap();
+    let mut accounts = vec![
         mango_program.to_owned(),
         //
         mango_group.to_owned(),
@@ -359,8 +1171,8 @@ pub fn adjust_position_spot<'info>(
         mango_signer.to_owned(),
         serum_dex_signer.to_owned(),
         srm_vault.to_owned(),
-        spot_open_orders.to_owned(),
     ];
+    accounts.extend_from_slice(open_orders);
     let meta_accounts = accounts
         .iter()
         .skip(1) // skip program id
@@ -391,6 +1203,294 @@ pub fn adjust_position_spot<'info>(
     Ok(())
 }
 
+pub fn redeem_mngo<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    perp_market: &AccountInfo<'info>,
+    mngo_perp_vault: &AccountInfo<'info>,
+    mngo_root_bank: &AccountInfo<'info>,
+    mngo_node_bank: &AccountInfo<'info>,
+    mngo_vault: &AccountInfo<'info>,
+    mango_signer: &AccountInfo<'info>,
+    token_program: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let accounts = vec![
+        mango_program.to_owned(),
+        //
+        mango_group.to_owned(),
+        mango_cache.to_owned(),
+        mango_account.to_owned(),
+        authority.to_owned(),
+        perp_market.to_owned(),
+        mngo_perp_vault.to_owned(),
+        mngo_root_bank.to_owned(),
+        mngo_node_bank.to_owned(),
+        mngo_vault.to_owned(),
+        mango_signer.to_owned(),
+        token_program.to_owned(),
+    ];
+    let meta_accounts = accounts
+        .iter()
+        .skip(1) // skip program id
+        .map(|x| {
+            x.to_account_metas(Some(x.key() == authority.key()))
+                .pop()
+                .unwrap()
+        })
+        .collect();
+    let instruction = Instruction {
+        program_id: mango_program.key(),
+        accounts: meta_accounts,
+        data: MangoInstruction::RedeemMngo.pack(),
+    };
+    invoke_signed(&instruction, &accounts, seeds)?;
+    Ok(())
+}
+
+/// Moves token deposits or borrows below one native token to the group dust account,
+/// so the mango account can be closed
+pub fn resolve_dust<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    authority: &AccountInfo<'info>,
+    dust_account: &AccountInfo<'info>,
+    root_bank: &AccountInfo<'info>,
+    node_bank: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    seeds: &[&[&[u8]]],
+) -> Result<()> {
+    let accounts = vec![
+        mango_program.to_owned(),
+        //
+        mango_group.to_owned(),
+        mango_account.to_owned(),
+        authority.to_owned(),
+        dust_account.to_owned(),
+        root_bank.to_owned(),
+        node_bank.to_owned(),
+        mango_cache.to_owned(),
+    ];
+    let meta_accounts = accounts
+        .iter()
+        .skip(1) // skip program id
+        .map(|x| {
+            x.to_account_metas(Some(x.key() == authority.key()))
+                .pop()
+                .unwrap()
+        })
+        .collect();
+    let instruction = Instruction {
+        program_id: mango_program.key(),
+        accounts: meta_accounts,
+        data: MangoInstruction::ResolveDust.pack(),
+    };
+    invoke_signed(&instruction, &accounts, seeds)?;
+    Ok(())
+}
+
+pub fn get_native_deposit<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    token_index: usize,
+) -> std::result::Result<I80F48, MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    mango_account.get_native_deposit(&mango_cache_data.root_bank_cache[token_index], token_index)
+}
+
+/// Limit price (in lots) and quantity (in base lots) to sell the whole deposit of the spot market token,
+/// limit price is at most `max_slippage_bps` below the oracle price. None if deposit is less than one lot.
+pub fn get_spot_sell_order<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    serum_dex: &AccountInfo<'info>,
+    spot_market: &AccountInfo<'info>,
+    max_slippage_bps: u16,
+) -> Result<Option<(u64, u64)>> {
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())
+        .map_err(ErrorCode::register_mango_error)?;
+    let market_index = mango_group_data
+        .find_spot_market_index(&spot_market.key())
+        .ok_or(ProgramError::InvalidArgument)?;
+    let deposit = get_native_deposit(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_index,
+    )
+    .map_err(ErrorCode::register_mango_error)?;
+    Ok(get_spot_order(
+        mango_program,
+        mango_group,
+        mango_cache,
+        serum_dex,
+        spot_market,
+        SerumSide::Ask,
+        deposit,
+        max_slippage_bps,
+    )?
+    .map(|(limit_price, base_lots, _max_quote_quantity)| (limit_price, base_lots)))
+}
+
+/// Limit price (in lots), quantity (in base lots) and max quote quantity (native, fees included)
+/// of a spot order for `base_quantity` native tokens. Limit price is at most `max_slippage_bps`
+/// worse than the oracle price. None if `base_quantity` is less than one lot.
+pub fn get_spot_order<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    serum_dex: &AccountInfo<'info>,
+    spot_market: &AccountInfo<'info>,
+    side: SerumSide,
+    base_quantity: I80F48,
+    max_slippage_bps: u16,
+) -> Result<Option<(u64, u64, u64)>> {
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())
+        .map_err(ErrorCode::register_mango_error)?;
+    let market_index = mango_group_data
+        .find_spot_market_index(&spot_market.key())
+        .ok_or(ProgramError::InvalidArgument)?;
+    let price = get_price(mango_cache, market_index)?;
+    let market = load_market_state(spot_market, &serum_dex.key())
+        .map_err(ErrorCode::register_mango_error)?;
+    let base_lots: u64 = base_quantity
+        .checked_div(I80F48::from_num(market.coin_lot_size))
+        .and_then(|lots| lots.checked_floor())
+        .and_then(|lots| lots.checked_cast())
+        .ok_or(ErrorCode::MathOverflow)?;
+    if base_lots == 0 {
+        return Ok(None);
+    }
+    let slippage = I80F48::from_num(max_slippage_bps) / I80F48::from_num(BPS_DENOMINATOR);
+    let lot_price = I80F48::from_num(market.coin_lot_size) / I80F48::from_num(market.pc_lot_size);
+    let limit_price = match side {
+        SerumSide::Bid => price
+            .checked_mul(I80F48::ONE + slippage)
+            .and_then(|price| price.checked_mul(lot_price))
+            .and_then(|price| price.checked_floor()),
+        SerumSide::Ask => price
+            .checked_mul(I80F48::ONE - slippage)
+            .and_then(|price| price.checked_mul(lot_price))
+            .and_then(|price| price.checked_ceil()),
+    }
+    .and_then(|price| price.checked_cast())
+    .ok_or(ErrorCode::MathOverflow)?;
+    let limit_price: u64 = limit_price.max(1);
+    // serum taker fees are at most 0.22%
+    let max_quote_quantity = limit_price
+        .checked_mul(base_lots)
+        .and_then(|quantity| quantity.checked_mul(market.pc_lot_size))
+        .and_then(|quantity| quantity.checked_mul(101))
+        .map(|quantity| quantity / 100)
+        .ok_or(ErrorCode::MathOverflow)?;
+    Ok(Some((limit_price, base_lots, max_quote_quantity)))
+}
+
+/// Limit price in quote lots per base lot, at most `max_slippage_bps` worse than the oracle price
+pub fn get_perp_limit_price<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    market_index: usize,
+    side: MangoSide,
+    max_slippage_bps: u16,
+) -> Result<i64> {
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())
+        .map_err(ErrorCode::register_mango_error)?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)
+            .map_err(ErrorCode::register_mango_error)?;
+    let perp_market_info = &mango_group_data.perp_markets[market_index];
+    let slippage = I80F48::from_num(max_slippage_bps) / I80F48::from_num(BPS_DENOMINATOR);
+    let lot_price = mango_cache_data
+        .get_price(market_index)
+        .checked_mul(I80F48::from_num(perp_market_info.base_lot_size))
+        .and_then(|price| price.checked_div(I80F48::from_num(perp_market_info.quote_lot_size)))
+        .ok_or(ErrorCode::MathOverflow)?;
+    let limit_price: i64 = match side {
+        MangoSide::Bid => lot_price
+            .checked_mul(I80F48::ONE + slippage)
+            .and_then(|price| price.checked_floor()),
+        MangoSide::Ask => lot_price
+            .checked_mul(I80F48::ONE - slippage)
+            .and_then(|price| price.checked_ceil()),
+    }
+    .and_then(|price| price.checked_cast())
+    .ok_or(ErrorCode::MathOverflow)?;
+    Ok(limit_price.max(1))
+}
+
+/// Maintenance health ratio in bps, (assets / liabilities - 1) * 10_000 with Mango maint weights.
+/// Counts USDC, the spot token and the perp market of the strategy, spot open orders are ignored.
+/// I80F48::MAX without liabilities.
+pub fn get_maint_health_ratio<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    market_info: &MarketInfo,
+) -> std::result::Result<I80F48, MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let net = |token_index: usize| -> std::result::Result<I80F48, MangoError> {
+        let root_bank_cache = &mango_cache_data.root_bank_cache[token_index];
+        Ok(
+            mango_account.get_native_deposit(root_bank_cache, token_index)?
+                - mango_account.get_native_borrow(root_bank_cache, token_index)?,
+        )
+    };
+    let mut assets = I80F48::ZERO;
+    let mut liabs = I80F48::ZERO;
+    let mut add = |value: I80F48, asset_weight: I80F48, liab_weight: I80F48| {
+        if value.is_positive() {
+            assets += value * asset_weight;
+        } else {
+            liabs -= value * liab_weight;
+        }
+    };
+    add(net(USDC_TOKEN_INDEX)?, I80F48::ONE, I80F48::ONE);
+    let spot_token_index = market_info.spot_token_index as usize;
+    let spot_market_info = &mango_group_data.spot_markets[market_info.spot_market_index as usize];
+    add(
+        net(spot_token_index)? * mango_cache_data.get_price(spot_token_index),
+        spot_market_info.maint_asset_weight,
+        spot_market_info.maint_liab_weight,
+    );
+    let perp_market_index = market_info.perp_market_index as usize;
+    let perp_market_info = &mango_group_data.perp_markets[perp_market_index];
+    let (perp_base, perp_quote) = mango_account.perp_accounts[perp_market_index].get_val(
+        perp_market_info,
+        &mango_cache_data.perp_market_cache[perp_market_index],
+        mango_cache_data.get_price(perp_market_index),
+    )?;
+    add(
+        perp_base,
+        perp_market_info.maint_asset_weight,
+        perp_market_info.maint_liab_weight,
+    );
+    add(perp_quote, I80F48::ONE, I80F48::ONE);
+    if liabs.is_zero() {
+        return Ok(I80F48::MAX);
+    }
+    Ok((assets / liabs - I80F48::ONE) * I80F48::from_num(BPS_DENOMINATOR))
+}
+
 pub fn calculate_tvl<'info>(
     mango_program: &AccountInfo<'info>,
     mango_group: &AccountInfo<'info>,
@@ -403,27 +1503,262 @@ pub fn calculate_tvl<'info>(
     let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
     let mango_cache_data =
         MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
-    let spot_token_balance = mango_account.get_native_deposit(
-        &mango_cache_data.root_bank_cache[market_info.spot_token_index as usize],
-        market_info.spot_token_index as usize,
+    let spot_token_index = market_info.spot_token_index as usize;
+    let spot_root_bank_cache = &mango_cache_data.root_bank_cache[spot_token_index];
+    // borrow index includes interest accrued by the root bank
+    let spot_token_balance = mango_account
+        .get_native_deposit(spot_root_bank_cache, spot_token_index)?
+        - mango_account.get_native_borrow(spot_root_bank_cache, spot_token_index)?;
+    let spot_token_price = mango_cache_data.get_price(spot_token_index);
+    let usdc_root_bank_cache = &mango_cache_data.root_bank_cache[USDC_TOKEN_INDEX];
+    let usdc_balance = mango_account.get_native_deposit(usdc_root_bank_cache, USDC_TOKEN_INDEX)?
+        - mango_account.get_native_borrow(usdc_root_bank_cache, USDC_TOKEN_INDEX)?;
+    Ok(usdc_balance + spot_token_balance * spot_token_price)
+}
+
+/// Deposits minus borrows of all tokens at oracle prices, perp positions are not included
+pub fn calculate_account_value<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+) -> std::result::Result<I80F48, MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let mut value = I80F48::ZERO;
+    for token_index in (0..mango_group_data.num_oracles).chain(std::iter::once(USDC_TOKEN_INDEX)) {
+        let root_bank_cache = &mango_cache_data.root_bank_cache[token_index];
+        value += (mango_account.get_native_deposit(root_bank_cache, token_index)?
+            - mango_account.get_native_borrow(root_bank_cache, token_index)?)
+            * mango_cache_data.get_price(token_index);
+    }
+    Ok(value)
+}
+
+/// Spot position value (borrowed or deposited) / tvl, I80F48::MAX if tvl <= 0
+pub fn calculate_leverage<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    market_info: &MarketInfo,
+) -> std::result::Result<I80F48, MangoError> {
+    let tvl = calculate_tvl(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_info,
     )?;
-    let spot_token_price = mango_cache_data.get_price(market_info.spot_token_index as usize);
-    let usdc_balance = mango_account.get_native_deposit(
-        &mango_cache_data.root_bank_cache[USDC_TOKEN_INDEX],
-        USDC_TOKEN_INDEX,
+    if tvl <= 0 {
+        return Ok(I80F48::MAX);
+    }
+    let (spot, _perp) = get_hedge_positions(
+        mango_program,
+        mango_group,
+        mango_account,
+        mango_cache,
+        market_info,
     )?;
-    Ok(usdc_balance + spot_token_balance * spot_token_price)
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let spot_value = spot.abs() * mango_cache_data.get_price(market_info.spot_token_index as usize);
+    Ok(spot_value / tvl)
 }
 
-pub fn calculate_token_price<'info>(
-    strategy_token_mint: &Account<'info, Mint>,
-    tvl: I80F48,
+/// Mango account health using the strategy spot market open orders
+pub fn get_health<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    spot_open_orders: &AccountInfo<'info>,
+    market_info: &MarketInfo,
+    health_type: HealthType,
 ) -> std::result::Result<I80F48, MangoError> {
-    let total_supply = I80F48::from_num(strategy_token_mint.supply);
-    if total_supply == I80F48::ZERO {
-        return Ok(I80F48::ONE);
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let mut open_orders: Vec<Option<&AccountInfo>> = vec![None; MAX_PAIRS];
+    if mango_account.spot_open_orders[market_info.spot_market_index as usize]
+        == spot_open_orders.key()
+    {
+        open_orders[market_info.spot_market_index as usize] = Some(spot_open_orders);
     }
-    Ok(tvl
+    let active_assets = UserActiveAssets::new(&mango_group_data, &mango_account, vec![]);
+    let mut health_cache = HealthCache::new(active_assets);
+    health_cache.init_vals_with_orders_vec(
+        &mango_group_data,
+        &mango_cache_data,
+        &mango_account,
+        &open_orders,
+    )?;
+    Ok(health_cache.get_health(&mango_group_data, health_type))
+}
+
+/// Spot deposit (negative if borrowed) and perp position (unprocessed taker trades included) of the strategy market, native base
+pub fn get_hedge_positions<'info>(
+    mango_program: &AccountInfo<'info>,
+    mango_group: &AccountInfo<'info>,
+    mango_account: &AccountInfo<'info>,
+    mango_cache: &AccountInfo<'info>,
+    market_info: &MarketInfo,
+) -> std::result::Result<(I80F48, I80F48), MangoError> {
+    let mango_account =
+        MangoAccount::load_checked(mango_account, &mango_program.key(), &mango_group.key())?;
+    let mango_group_data = MangoGroup::load_checked(mango_group, &mango_program.key())?;
+    let mango_cache_data =
+        MangoCache::load_checked(mango_cache, &mango_program.key(), &mango_group_data)?;
+    let spot_token_index = market_info.spot_token_index as usize;
+    let spot_root_bank_cache = &mango_cache_data.root_bank_cache[spot_token_index];
+    let spot = mango_account.get_native_deposit(spot_root_bank_cache, spot_token_index)?
+        - mango_account.get_native_borrow(spot_root_bank_cache, spot_token_index)?;
+    let perp_market_index = market_info.perp_market_index as usize;
+    let perp_account = &mango_account.perp_accounts[perp_market_index];
+    let perp = I80F48::from_num(
+        (perp_account.base_position + perp_account.taker_base)
+            * mango_group_data.perp_markets[perp_market_index].base_lot_size,
+    );
+    Ok((spot, perp))
+}
+
+/// Deviation from the target hedge (spot * ratio + perp) and the allowed deviation, native base
+pub fn calculate_delta(
+    spot: I80F48,
+    perp: I80F48,
+    target_hedge_ratio_bps: u16,
+    delta_tolerance_bps: u16,
+) -> (I80F48, I80F48) {
+    let bps = I80F48::from_num(BPS_DENOMINATOR);
+    let delta = spot * I80F48::from_num(target_hedge_ratio_bps) / bps + perp;
+    let band = spot.abs() * I80F48::from_num(delta_tolerance_bps) / bps;
+    (delta, band)
+}
+
+/// Virtual shares and assets make the empty vault behave as if it already held 1 USDC / 1 share,
+/// so donations to the Mango account can't inflate the share price enough to round deposits to zero
+pub const VIRTUAL_SHARES: u64 = 1_000000;
+pub const VIRTUAL_ASSETS: u64 = 1_000000;
+
+/// Vault tokens per strategy token, fails if the TVL is not above `-VIRTUAL_ASSETS`
+pub fn calculate_token_price(supply: u64, tvl: I80F48) -> Result<I80F48> {
+    let (total_supply, total_assets) = get_share_totals(supply, tvl)?;
+    Ok(total_assets
         .checked_div(total_supply)
-        .expect("tvl / total_supply failed"))
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+/// Strategy tokens minted for `vault_token_amount`, rounded down
+pub fn calculate_shares_for_deposit(
+    supply: u64,
+    tvl: I80F48,
+    vault_token_amount: u64,
+) -> Result<u64> {
+    let (total_supply, total_assets) = get_share_totals(supply, tvl)?;
+    Ok(total_supply
+        .checked_div(total_assets)
+        .and_then(|shares_per_token| {
+            I80F48::from_num(vault_token_amount).checked_mul(shares_per_token)
+        })
+        .and_then(|shares| shares.checked_floor())
+        .and_then(|shares| shares.checked_cast())
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+/// Vault tokens paid for burning `strategy_token_amount`, rounded down
+/// (i.e. the shares burned per vault token are rounded up)
+pub fn calculate_vault_tokens_for_withdraw(
+    supply: u64,
+    tvl: I80F48,
+    strategy_token_amount: u64,
+) -> Result<u64> {
+    let token_price = calculate_token_price(supply, tvl)?;
+    Ok(I80F48::from_num(strategy_token_amount)
+        .checked_mul(token_price)
+        .and_then(|vault_tokens| vault_tokens.checked_floor())
+        .and_then(|vault_tokens| vault_tokens.checked_cast())
+        .ok_or(ErrorCode::MathOverflow)?)
+}
+
+/// Supply and assets including the virtual shares and assets
+fn get_share_totals(supply: u64, tvl: I80F48) -> Result<(I80F48, I80F48)> {
+    let total_assets = tvl
+        .checked_add(I80F48::from_num(VIRTUAL_ASSETS))
+        .filter(|total_assets| total_assets.is_positive())
+        .ok_or(ErrorCode::InvalidTvl)?;
+    let total_supply = I80F48::from_num(supply) + I80F48::from_num(VIRTUAL_SHARES);
+    Ok((total_supply, total_assets))
+}
+
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Fee on `amount`, rounded up in favour of remaining holders
+pub fn calculate_fee(amount: u64, fee_bps: u16) -> u64 {
+    let denominator = BPS_DENOMINATOR as u128;
+    ((amount as u128 * fee_bps as u128 + denominator - 1) / denominator) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const MAX_AMOUNT: u64 = 1_000_000_000_000000; // 1B USDC
+
+    proptest! {
+        #[test]
+        fn deposit_then_withdraw_returns_at_most_the_deposit(
+            supply in 0..MAX_AMOUNT,
+            tvl in 0..MAX_AMOUNT,
+            amount in 0..MAX_AMOUNT,
+        ) {
+            let tvl = I80F48::from_num(tvl);
+            let shares = calculate_shares_for_deposit(supply, tvl, amount);
+            prop_assume!(shares.is_ok()); // overflow for huge prices
+            let shares = shares.unwrap();
+            let withdrawn = calculate_vault_tokens_for_withdraw(
+                supply + shares,
+                tvl + I80F48::from_num(amount),
+                shares,
+            )
+            .unwrap();
+            prop_assert!(withdrawn <= amount);
+        }
+
+        #[test]
+        fn withdraw_then_deposit_returns_at_most_the_shares(
+            supply in 0..MAX_AMOUNT,
+            tvl in 0..MAX_AMOUNT,
+            shares_bps in 0..=BPS_DENOMINATOR,
+        ) {
+            let shares = supply / BPS_DENOMINATOR * shares_bps;
+            let tvl = I80F48::from_num(tvl);
+            let withdrawn = calculate_vault_tokens_for_withdraw(supply, tvl, shares).unwrap();
+            let redeposited = calculate_shares_for_deposit(
+                supply - shares,
+                tvl - I80F48::from_num(withdrawn),
+                withdrawn,
+            );
+            prop_assume!(redeposited.is_ok());
+            prop_assert!(redeposited.unwrap() <= shares);
+        }
+
+        #[test]
+        fn tvl_below_virtual_assets_fails(
+            supply in 0..MAX_AMOUNT,
+            tvl in -(MAX_AMOUNT as i64)..=-(VIRTUAL_ASSETS as i64),
+            amount in 0..MAX_AMOUNT,
+        ) {
+            let tvl = I80F48::from_num(tvl);
+            prop_assert!(calculate_token_price(supply, tvl).is_err());
+            prop_assert!(calculate_shares_for_deposit(supply, tvl, amount).is_err());
+            prop_assert!(calculate_vault_tokens_for_withdraw(supply, tvl, amount).is_err());
+        }
+    }
 }
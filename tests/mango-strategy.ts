@@ -2,8 +2,10 @@ import assert from 'assert';
 import * as anchor from '@project-serum/anchor';
 import { Program, BN } from '@project-serum/anchor';
 import { MangoStrategy } from '../target/types/mango_strategy';
-import { SystemProgram, SYSVAR_RENT_PUBKEY, PublicKey } from '@solana/web3.js';
-import { TOKEN_PROGRAM_ID, getOrCreateAssociatedTokenAccount } from '@solana/spl-token';
+import { SystemProgram, SYSVAR_RENT_PUBKEY, PublicKey, LAMPORTS_PER_SOL, Transaction, sendAndConfirmTransaction } from '@solana/web3.js';
+import { TOKEN_PROGRAM_ID, getAccount, getMint, getOrCreateAssociatedTokenAccount, transfer } from '@solana/spl-token';
+import { Config, I80F48, IDS, MangoClient } from '@blockworks-foundation/mango-client';
+import { Market } from '@project-serum/serum';
 import { utf8 } from '@project-serum/anchor/dist/cjs/utils/bytes';
 import fs from 'fs';
 
@@ -47,6 +49,315 @@ describe('mango-strategy', () => {
   const positionSize = 15; // 0.015 ETH
   const depositAmount = 80_000000; // 80 USDC
 
+  const connection = anchor.getProvider().connection;
+
+  // Mango accounts of the test markets (same as in adjustPositionSpot and adjustPositionPerp below)
+  const mango = {
+    mangoCache: new PublicKey("8mFQbdXsFXt3R3cu3oSNS3bDZRwJRP18vyzd9J278J9z"),
+    mangoRootBank: new PublicKey("HUBX4iwWEUK5VrXXXcB7uhuKrfT4fpu2T9iZbg712JrN"),
+    mangoNodeBank: new PublicKey("J2Lmnc1e4frMnBEJARPoHtfpcohLfN67HdK1inXjTFSM"),
+    mangoVault: new PublicKey("AV4CuwdvnccZMXNhu9cSCx1mkpgHWcwWEJ7Yb8Xh8QMC"),
+    mangoSigner: new PublicKey("CFdbPXrnPLmo5Qrze7rw9ZNiD82R1VeNdoQosooSP1Ax"),
+    mangoMarket: new PublicKey("8jKPf3KJKWvvSbbYnunwZYv62UoRPpyGb93NWLaswzcS"),
+    mangoAsks: new PublicKey("FXSvghvoaWFHRXzWUHi5tjK9YhgcPgMPpypFXBd4Aq3r"),
+    mangoBids: new PublicKey("6jGBscmZgRXk6oVLWbnQDpRftmzrDVu82TARci9VHKuW"),
+    mangoEventQueue: new PublicKey("8WLv5fKLYkyZpFG74kRmp2RALHQFcNKmH7eJn8ebHC13"),
+    spotAsks: new PublicKey("3pfYeG2GKSh8SSZJEEwjYqgaHwYkq5vvSDET2M33nQAf"),
+    spotBids: new PublicKey("ETf3PZi9VaBsfpMU5e3SAn4SMjkaM6tyrn2Td9N2kSRx"),
+    spotRequestQueue: new PublicKey("9hzYZxqP4itrzPPSCSqPGkSbkbSE2gqri4kw5mWQ2Jj1"),
+    spotEventQueue: new PublicKey("F43gimmdvBPQoGA4eDxt2N2ooiYWHvQ8pEATrtsArKuC"),
+    spotBase: new PublicKey("AXBJBqj9m9bxLxjyDtfqt19WWna7jijDawjgRDFXXfB3"),
+    spotQuote: new PublicKey("Dh8w8pwvfQM5zYW1PzEFQNip8vwYVHYuZo53hFPRWTs6"),
+    spotBaseRootBank: new PublicKey("AxwY5sgwSq5Uh8GD6A6ZtSzGd5fqvW2hwgGLLgZ4v2eW"),
+    spotBaseNodeBank: new PublicKey("3FPjawEtvrwvwtAetaURTbkkucu9BJofxWZUNPGHJtHg"),
+    spotBaseVault: new PublicKey("BzNgzZ9o8eAW3KZZ47YutwhrPw24DQz4SqJ2EyvPpxMp"),
+    serumDexSigner: new PublicKey("Cxs1KorP4Dwqbn1R9FgZyQ4pT51woNnkg2GxyQgZ3ude"),
+  };
+
+  // Strategy PDAs, seeds as in the README
+  const strategyPdas = async () => {
+    const pda = async (seed: string, ...extra: Buffer[]) => (await PublicKey.findProgramAddress(
+      [strategyId.publicKey.toBuffer(), utf8.encode(seed), ...extra],
+      program.programId
+    ))[0];
+    const [strategyAccount, strategyAccountBump] = await PublicKey.findProgramAddress(
+      [strategyId.publicKey.toBuffer(), utf8.encode("account")],
+      program.programId
+    );
+    const [mangoAccount, _mangoBump] = await PublicKey.findProgramAddress(
+      [mangoGroup.toBytes(), strategyAccount.toBytes(), new BN(accountNum).toBuffer('le', 8)],
+      mangoProgram,
+    );
+    const [spotOpenOrders, _spotOpenOrdersBump] = await PublicKey.findProgramAddress(
+      [mangoAccount.toBuffer(), new BN(2).toBuffer('le', 8), utf8.encode("OpenOrders")],
+      mangoProgram
+    );
+    return {
+      bumps: { strategyAccountBump },
+      strategyAccount,
+      mangoAccount,
+      spotOpenOrders,
+      vaultTokenAccount: await pda("vault"),
+      strategyTokenMint: await pda("mint"),
+      depositReceipt: await pda("receipt", owner.publicKey.toBuffer()),
+      sharePriceHistory: await pda("history"),
+      navAccount: await pda("nav"),
+      userPosition: await pda("position", owner.publicKey.toBuffer()),
+      pendingChange: await pda("pending"),
+      depositQueue: await pda("deposit_queue"),
+      pendingVaultTokenAccount: await pda("pending_vault"),
+      triggerDelegate: await pda("delegate"),
+    };
+  };
+
+  type StrategyPdas = Awaited<ReturnType<typeof strategyPdas>>;
+
+  // Mango account `num` of the strategy and its spot open orders
+  const mangoAccountPdas = async (p: StrategyPdas, num: number) => {
+    const [mangoAccount, _mangoBump] = await PublicKey.findProgramAddress(
+      [mangoGroup.toBytes(), p.strategyAccount.toBytes(), new BN(num).toBuffer('le', 8)],
+      mangoProgram,
+    );
+    const [spotOpenOrders, _spotOpenOrdersBump] = await PublicKey.findProgramAddress(
+      [mangoAccount.toBuffer(), new BN(2).toBuffer('le', 8), utf8.encode("OpenOrders")],
+      mangoProgram
+    );
+    return { mangoAccount, spotOpenOrders };
+  };
+
+  // Additional Mango accounts (accountNum > 1), remaining accounts of the instructions valuing the strategy
+  const extraMangoAccounts = async (p: StrategyPdas) => {
+    const { mangoAccountCount } = await program.account.strategyAccount.fetch(p.strategyAccount);
+    const metas = [];
+    for (let num = accountNum + 1; num <= mangoAccountCount.toNumber(); num++) {
+      const { mangoAccount } = await mangoAccountPdas(p, num);
+      metas.push({ isSigner: false, isWritable: false, pubkey: mangoAccount });
+    }
+    return metas;
+  };
+
+  // Accounts of the owner setters
+  const ownerAccounts = (p: StrategyPdas) => ({
+    strategyId: strategyId.publicKey,
+    owner: owner.publicKey,
+    strategyAccount: p.strategyAccount,
+  });
+
+  // Accounts of adjustPositionPerp and placePerpOrder
+  const perpAccounts = (p: StrategyPdas) => ({
+    strategyId: strategyId.publicKey,
+    triggerServer: triggerServer.publicKey,
+    strategyAccount: p.strategyAccount,
+    navAccount: p.navAccount,
+    strategyTokenMint: p.strategyTokenMint,
+    mangoProgram,
+    mangoGroup,
+    mangoAccount: p.mangoAccount,
+    mangoCache: mango.mangoCache,
+    mangoRootBank: mango.mangoRootBank,
+    mangoNodeBank: mango.mangoNodeBank,
+    mangoVault: mango.mangoVault,
+    mangoMarket: mango.mangoMarket,
+    mangoAsks: mango.mangoAsks,
+    mangoBids: mango.mangoBids,
+    mangoEventQueue: mango.mangoEventQueue,
+    mangoSigner: mango.mangoSigner,
+    spotOpenOrders: p.spotOpenOrders,
+    systemProgram: SystemProgram.programId,
+    tokenProgram: TOKEN_PROGRAM_ID,
+  });
+
+  const adjustPositionPerp = async (p: StrategyPdas, amount: number) =>
+    program.rpc.adjustPositionPerp(p.bumps, new BN(amount), false, {
+      accounts: perpAccounts(p),
+      remainingAccounts: await extraMangoAccounts(p),
+      signers: [triggerServer]
+    });
+
+  // Accounts of adjustPositionSpot
+  const spotAccounts = (p: StrategyPdas) => ({
+    strategyId: strategyId.publicKey,
+    triggerServer: triggerServer.publicKey,
+    strategyAccount: p.strategyAccount,
+    navAccount: p.navAccount,
+    strategyTokenMint: p.strategyTokenMint,
+    mangoProgram,
+    mangoGroup,
+    mangoAccount: p.mangoAccount,
+    mangoCache: mango.mangoCache,
+    mangoSigner: mango.mangoSigner,
+    serumDex,
+    spotMarket,
+    spotOpenOrders: p.spotOpenOrders,
+    spotAsks: mango.spotAsks,
+    spotBids: mango.spotBids,
+    spotRequestQueue: mango.spotRequestQueue,
+    spotEventQueue: mango.spotEventQueue,
+    spotBase: mango.spotBase,
+    spotQuote: mango.spotQuote,
+    spotBaseRootBank: mango.spotBaseRootBank,
+    spotBaseNodeBank: mango.spotBaseNodeBank,
+    spotBaseVault: mango.spotBaseVault,
+    spotQuoteRootBank: mango.mangoRootBank,
+    spotQuoteNodeBank: mango.mangoNodeBank,
+    spotQuoteVault: mango.mangoVault,
+    serumDexSigner: mango.serumDexSigner,
+    srmVault: PublicKey.default,
+    tokenProgram: TOKEN_PROGRAM_ID,
+  });
+
+  const adjustPositionSpot = async (p: StrategyPdas, amount: number) =>
+    program.rpc.adjustPositionSpot(p.bumps, new BN(amount), {
+      accounts: spotAccounts(p),
+      remainingAccounts: await extraMangoAccounts(p),
+      signers: [triggerServer]
+    });
+
+  const cancelPerpOrdersAccounts = (p: StrategyPdas) => ({
+    strategyId: strategyId.publicKey,
+    triggerServer: triggerServer.publicKey,
+    strategyAccount: p.strategyAccount,
+    mangoProgram,
+    mangoGroup,
+    mangoAccount: p.mangoAccount,
+    mangoMarket: mango.mangoMarket,
+    mangoAsks: mango.mangoAsks,
+    mangoBids: mango.mangoBids,
+  });
+
+  // Accounts of the permissionless keeper instructions, called by the owner in the tests
+  const keeperAccounts = async (p: StrategyPdas) => ({
+    strategyId: strategyId.publicKey,
+    caller: owner.publicKey,
+    strategyAccount: p.strategyAccount,
+    navAccount: p.navAccount,
+    strategyTokenMint: p.strategyTokenMint,
+    callerStrategyTokenAccount: (await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, owner.publicKey)).address,
+    mangoProgram,
+    mangoGroup,
+    mangoAccount: p.mangoAccount,
+    mangoCache: mango.mangoCache,
+    mangoSigner: mango.mangoSigner,
+    mangoMarket: mango.mangoMarket,
+    mangoAsks: mango.mangoAsks,
+    mangoBids: mango.mangoBids,
+    mangoEventQueue: mango.mangoEventQueue,
+    serumDex,
+    spotMarket,
+    spotOpenOrders: p.spotOpenOrders,
+    spotAsks: mango.spotAsks,
+    spotBids: mango.spotBids,
+    spotRequestQueue: mango.spotRequestQueue,
+    spotEventQueue: mango.spotEventQueue,
+    spotBase: mango.spotBase,
+    spotQuote: mango.spotQuote,
+    spotBaseRootBank: mango.spotBaseRootBank,
+    spotBaseNodeBank: mango.spotBaseNodeBank,
+    spotBaseVault: mango.spotBaseVault,
+    spotQuoteRootBank: mango.mangoRootBank,
+    spotQuoteNodeBank: mango.mangoNodeBank,
+    spotQuoteVault: mango.mangoVault,
+    serumDexSigner: mango.serumDexSigner,
+    srmVault: PublicKey.default,
+    tokenProgram: TOKEN_PROGRAM_ID,
+  });
+
+  // Keeper instructions disabled, the tests enable them one at a time
+  const keeperConfig = {
+    deleverageHealthRatioBps: 0,
+    rebalanceDriftBps: 0,
+    maxSlippageBps: 100,
+    bounty: new BN(100000), // 0.1 USDC
+    minHealthImprovementBps: 0,
+    cooldown: new BN(3600),
+    minDriftImprovementBps: 0,
+  };
+
+  // Accounts of deposit and withdraw for `user`, the owner unless a test needs a second depositor
+  const userAccounts = async (p: StrategyPdas, user: PublicKey = owner.publicKey) => {
+    const pda = async (seed: string) => (await PublicKey.findProgramAddress(
+      [strategyId.publicKey.toBuffer(), utf8.encode(seed), user.toBuffer()],
+      program.programId
+    ))[0];
+    const usdcTokenAccount = (await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, user)).address;
+    const strategyTokenAccount = (await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, user)).address;
+    const accounts = {
+      owner: user,
+      strategyId: strategyId.publicKey,
+      strategyAccount: p.strategyAccount,
+      mangoProgram,
+      mangoGroup,
+      mangoAccount: p.mangoAccount,
+      mangoCache: mango.mangoCache,
+      mangoRootBank: mango.mangoRootBank,
+      mangoNodeBank: mango.mangoNodeBank,
+      mangoVault: mango.mangoVault,
+      strategyTokenMint: p.strategyTokenMint,
+      strategyTokenAccount,
+      depositReceipt: await pda("receipt"),
+      sharePriceHistory: p.sharePriceHistory,
+      userPosition: await pda("position"),
+      navAccount: p.navAccount,
+      tokenProgram: TOKEN_PROGRAM_ID,
+      systemProgram: SystemProgram.programId,
+    };
+    return {
+      usdcTokenAccount,
+      strategyTokenAccount,
+      deposit: { ...accounts, depositTokenAccount: usdcTokenAccount },
+      withdraw: { ...accounts, mangoSigner: mango.mangoSigner, spotOpenOrders: p.spotOpenOrders, withdrawTokenAccount: usdcTokenAccount },
+    };
+  };
+
+  // Remaining accounts of deposit and withdraw: the limits account and the additional Mango accounts
+  const userRemainingAccounts = async (p: StrategyPdas) =>
+    [{ isSigner: false, isWritable: true, pubkey: limitsAccount.publicKey }, ...await extraMangoAccounts(p)];
+
+  // Events emitted by the confirmed transaction `signature`
+  const txEvents = async (signature: string) => {
+    const tx = await connection.getTransaction(signature, { commitment: 'confirmed' });
+    const events = [];
+    new anchor.EventParser(program.programId, program.coder).parseLogs(tx.meta.logMessages, e => events.push(e));
+    return events;
+  };
+
+  // calculate_fee: rounded up in favour of the remaining holders
+  const feeOf = (amount: BN, feeBps: number) => amount.muln(feeBps).addn(9999).divn(10000);
+
+  const tokenBalance = async (tokenAccount: PublicKey) => (await getAccount(connection, tokenAccount)).amount;
+
+  const strategyTokenBalance = async (p: StrategyPdas) =>
+    (await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, owner.publicKey)).amount;
+
+  // Fails the test unless `promise` fails with the program error `errorCode`
+  const assertFails = async (promise: Promise<any>, errorCode: string) => {
+    try {
+      await promise;
+    } catch (e) {
+      const message = String(e) + (e.logs ?? []).join("\n");
+      assert(message.includes(errorCode), `Expected ${errorCode}, got ${message}`);
+      return;
+    }
+    assert.fail(`Expected ${errorCode}`);
+  };
+
+  // MNGO/USDC spot market and the MNGO open orders of a strategy Mango account (harvestMngo)
+  const mngoSpot = async (mangoAccount: PublicKey) => {
+    const groupConfig = new Config(IDS).groups.find(g => g.publicKey.equals(mangoGroup));
+    const marketConfig = groupConfig.spotMarkets.find(m => m.baseSymbol === "MNGO");
+    const group = await new MangoClient(connection, mangoProgram).getMangoGroup(mangoGroup);
+    const market = await Market.load(connection, marketConfig.publicKey, {}, serumDex);
+    const [openOrders, _openOrdersBump] = await PublicKey.findProgramAddress(
+      [mangoAccount.toBuffer(), new BN(group.getSpotMarketIndex(marketConfig.publicKey)).toBuffer('le', 8), utf8.encode("OpenOrders")],
+      mangoProgram
+    );
+    const serumDexSigner = await PublicKey.createProgramAddress(
+      [market.publicKey.toBuffer(), market.decoded.vaultSignerNonce.toArrayLike(Buffer, 'le', 8)],
+      serumDex
+    );
+    return { market, openOrders, serumDexSigner };
+  };
+
   it('Initialize', async () => {
     console.log("Program:", program.programId.toBase58());
     const [strategyAccount, strategyAccountBump] = await PublicKey.findProgramAddress(
@@ -78,6 +389,24 @@ describe('mango-strategy', () => {
       [strategyId.publicKey.toBuffer(), utf8.encode("mint")],
       program.programId
     );
+    const [sharePriceHistory, _historyBump] = await PublicKey.findProgramAddress(
+      [strategyId.publicKey.toBuffer(), utf8.encode("history")],
+      program.programId
+    );
+    const [navAccount, _navBump] = await PublicKey.findProgramAddress(
+      [strategyId.publicKey.toBuffer(), utf8.encode("nav")],
+      program.programId
+    );
+    const [registry, _registryBump] = await PublicKey.findProgramAddress(
+      [utf8.encode("registry")],
+      program.programId
+    );
+    const registryState = await program.account.registry.fetchNullable(registry);
+    const registryPageIndex = registryState ? registryState.strategyCount.divn(64) : new BN(0);
+    const [registryPage, _registryPageBump] = await PublicKey.findProgramAddress(
+      [utf8.encode("registry"), registryPageIndex.toBuffer('le', 8)],
+      program.programId
+    );
 
     console.log("Strategy id:", strategyId.publicKey.toBase58());
     console.log("Strategy account:", strategyAccount.toBase58());
@@ -98,6 +427,10 @@ describe('mango-strategy', () => {
         strategyId: strategyId.publicKey,
         triggerServer: triggerServer.publicKey,
         strategyAccount,
+        sharePriceHistory,
+        navAccount,
+        registry,
+        registryPage,
         mangoProgram,
         mangoGroup,
         mangoAccount,
@@ -175,6 +508,22 @@ describe('mango-strategy', () => {
       const bumps = {
         strategyAccountBump,
       };
+      const [depositReceipt, _receiptBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("receipt"), owner.publicKey.toBuffer()],
+        program.programId
+      );
+      const [sharePriceHistory, _historyBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("history")],
+        program.programId
+      );
+      const [navAccount, _navBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("nav")],
+        program.programId
+      );
+      const [userPosition, _positionBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("position"), owner.publicKey.toBuffer()],
+        program.programId
+      );
 
       const strategyTokenAccount = await getOrCreateAssociatedTokenAccount(anchor.getProvider().connection, owner, strategyTokenMint, owner.publicKey);
       const strategyTokenBalanceBefore = strategyTokenAccount.amount;
@@ -184,7 +533,7 @@ describe('mango-strategy', () => {
       assert(usdcBalanceBefore >= depositAmount, "Account balance < 100 USDC");
 
 
-      await program.rpc.deposit(bumps, new anchor.BN(depositAmount), {
+      await program.rpc.deposit(bumps, new anchor.BN(depositAmount), new anchor.BN(0), {
         accounts: {
           owner: owner.publicKey,
           strategyId: strategyId.publicKey,
@@ -200,7 +549,12 @@ describe('mango-strategy', () => {
           depositTokenAccount: usdcTokenAccount.address,
           strategyTokenMint,
           strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt,
+          sharePriceHistory,
+          userPosition,
+          navAccount,
           tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
         },
         remainingAccounts: [{ isSigner: false, isWritable: true, pubkey: limitsAccount.publicKey }],
         signers: [owner],
@@ -239,11 +593,14 @@ describe('mango-strategy', () => {
         strategyAccountBump
       };
 
+      const p = await strategyPdas();
       await program.rpc.adjustPositionSpot(bumps, new anchor.BN(positionSize), { // long
         accounts: {
           strategyId: strategyId.publicKey,
           triggerServer: triggerServer.publicKey,
           strategyAccount,
+          navAccount: p.navAccount,
+          strategyTokenMint: p.strategyTokenMint,
           mangoProgram,
           mangoGroup,
           mangoAccount,
@@ -297,11 +654,14 @@ describe('mango-strategy', () => {
         strategyAccountBump,
       };
 
+      const p = await strategyPdas();
       await program.rpc.adjustPositionPerp(bumps, new anchor.BN(-positionSize), false, { // short
         accounts: {
           strategyId: strategyId.publicKey,
           triggerServer: triggerServer.publicKey,
           strategyAccount,
+          navAccount: p.navAccount,
+          strategyTokenMint: p.strategyTokenMint,
           mangoProgram,
           mangoGroup,
           mangoAccount,
@@ -350,6 +710,22 @@ describe('mango-strategy', () => {
       const bumps = {
         strategyAccountBump
       };
+      const [depositReceipt, _receiptBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("receipt"), owner.publicKey.toBuffer()],
+        program.programId
+      );
+      const [sharePriceHistory, _historyBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("history")],
+        program.programId
+      );
+      const [navAccount, _navBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("nav")],
+        program.programId
+      );
+      const [userPosition, _positionBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("position"), owner.publicKey.toBuffer()],
+        program.programId
+      );
 
       const usdcTokenAccount = await getOrCreateAssociatedTokenAccount(anchor.getProvider().connection, owner, usdcMint, owner.publicKey);
       const strategyTokenAccount = await getOrCreateAssociatedTokenAccount(anchor.getProvider().connection, owner, strategyTokenMint, owner.publicKey);
@@ -359,7 +735,7 @@ describe('mango-strategy', () => {
 
       const withdrawAmount = 10_000000;
 
-      await program.rpc.withdraw(bumps, new anchor.BN(withdrawAmount), {
+      await program.rpc.withdraw(bumps, new anchor.BN(withdrawAmount), new anchor.BN(0), {
         accounts: {
           owner: owner.publicKey,
           strategyId: strategyId.publicKey,
@@ -376,6 +752,10 @@ describe('mango-strategy', () => {
           withdrawTokenAccount: usdcTokenAccount.address,
           strategyTokenMint: strategyTokenMint,
           strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt,
+          sharePriceHistory,
+          userPosition,
+          navAccount,
           systemProgram: SystemProgram.programId,
           tokenProgram: TOKEN_PROGRAM_ID,
         },
@@ -391,5 +771,1084 @@ describe('mango-strategy', () => {
         "Invalid token balance change after withdraw"
       );
     });
+
+    it('Outflow limit', async () => {
+      const p = await strategyPdas();
+      const usdcTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, owner.publicKey);
+      const strategyTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, owner.publicKey);
+      const withdraw = (amount: number) => program.rpc.withdraw(p.bumps, new BN(amount), new BN(0), {
+        accounts: {
+          owner: owner.publicKey,
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          mangoRootBank: mango.mangoRootBank,
+          mangoNodeBank: mango.mangoNodeBank,
+          mangoVault: mango.mangoVault,
+          mangoSigner: mango.mangoSigner,
+          spotOpenOrders: p.spotOpenOrders,
+          withdrawTokenAccount: usdcTokenAccount.address,
+          strategyTokenMint: p.strategyTokenMint,
+          strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt: p.depositReceipt,
+          sharePriceHistory: p.sharePriceHistory,
+          userPosition: p.userPosition,
+          navAccount: p.navAccount,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        remainingAccounts: [{ isSigner: false, isWritable: true, pubkey: limitsAccount.publicKey }],
+        signers: [owner],
+      });
+      const setOutflowLimit = (maxOutflowBps: number) => program.rpc.setOutflowLimit(p.bumps, maxOutflowBps, new BN(3600), {
+        accounts: { strategyId: strategyId.publicKey, owner: owner.publicKey, strategyAccount: p.strategyAccount },
+        signers: [owner],
+      });
+
+      await setOutflowLimit(100); // 1% of ~70 USDC per hour
+      await assertFails(withdraw(1_000000), "OutflowLimitReached");
+      await withdraw(400000);
+      // the first withdrawal still counts in the window
+      await assertFails(withdraw(400000), "OutflowLimitReached");
+
+      await setOutflowLimit(0);
+      await withdraw(400000);
+    });
+
+    it('Harvest MNGO', async () => {
+      const p = await strategyPdas();
+      const groupConfig = new Config(IDS).groups.find(g => g.publicKey.equals(mangoGroup));
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const rootBanks = await group.loadRootBanks(connection);
+      const perpMarketConfig = groupConfig.perpMarkets.find(m => m.publicKey.equals(mango.mangoMarket));
+      const perpMarket = await client.getPerpMarket(mango.mangoMarket, perpMarketConfig.baseDecimals, perpMarketConfig.quoteDecimals);
+      const mngoIndex = group.getTokenIndex(groupConfig.tokens.find(t => t.symbol === "MNGO").mintKey);
+      const { market, openOrders, serumDexSigner } = await mngoSpot(p.mangoAccount);
+      const harvestMngo = (maxSlippageBps: number) => program.rpc.harvestMngo(p.bumps, maxSlippageBps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          triggerServer: triggerServer.publicKey,
+          strategyAccount: p.strategyAccount,
+          navAccount: p.navAccount,
+          strategyTokenMint: p.strategyTokenMint,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          mangoSigner: mango.mangoSigner,
+          mangoMarket: mango.mangoMarket,
+          mngoPerpVault: perpMarket.mngoVault,
+          serumDex,
+          spotMarket: market.publicKey,
+          spotOpenOrders: p.spotOpenOrders,
+          mngoSpotOpenOrders: openOrders,
+          spotAsks: market.asksAddress,
+          spotBids: market.bidsAddress,
+          spotRequestQueue: market.decoded.requestQueue,
+          spotEventQueue: market.decoded.eventQueue,
+          spotBase: market.decoded.baseVault,
+          spotQuote: market.decoded.quoteVault,
+          spotBaseRootBank: rootBanks[mngoIndex].publicKey,
+          spotBaseNodeBank: rootBanks[mngoIndex].nodeBankAccounts[0].publicKey,
+          spotBaseVault: rootBanks[mngoIndex].nodeBankAccounts[0].vault,
+          spotQuoteRootBank: mango.mangoRootBank,
+          spotQuoteNodeBank: mango.mangoNodeBank,
+          spotQuoteVault: mango.mangoVault,
+          serumDexSigner,
+          srmVault: PublicKey.default,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        },
+        signers: [triggerServer]
+      });
+
+      await assertFails(harvestMngo(10001), "InvalidSlippage");
+      await harvestMngo(100);
+      assert((await connection.getAccountInfo(openOrders)) !== null, "MNGO open orders not created");
+    });
+
+    it('Maker orders', async () => {
+      const p = await strategyPdas();
+      const groupConfig = new Config(IDS).groups.find(g => g.publicKey.equals(mangoGroup));
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const cache = await group.loadCache(connection);
+      const perpMarketConfig = groupConfig.perpMarkets.find(m => m.publicKey.equals(mango.mangoMarket));
+      const perpMarket = await client.getPerpMarket(mango.mangoMarket, perpMarketConfig.baseDecimals, perpMarketConfig.quoteDecimals);
+      const price = group.getPriceUi(perpMarketConfig.marketIndex, cache);
+      // far from the oracle price, the orders rest on the book
+      const [bidPrice, _bidQuantity] = perpMarket.uiToNativePriceQuantity(price * 0.5, 0);
+      const [askPrice, _askQuantity] = perpMarket.uiToNativePriceQuantity(price * 1.5, 0);
+      const restingOrders = async () => [...await perpMarket.loadBids(connection), ...await perpMarket.loadAsks(connection)]
+        .filter(order => order.owner.equals(p.mangoAccount));
+      const setMaxPerpPosition = (maxPerpPosition: number) => program.rpc.setMaxPerpPosition(p.bumps, new BN(maxPerpPosition), {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+
+      await program.rpc.placePerpOrder(p.bumps, bidPrice, new BN(1), new BN(1), { postOnly: {} }, true, {
+        accounts: perpAccounts(p),
+        signers: [triggerServer]
+      });
+      assert((await restingOrders()).some(order => order.clientId.eqn(1)), "Perp order not placed");
+      await program.rpc.cancelPerpOrders(p.bumps, new BN(1), {
+        accounts: cancelPerpOrdersAccounts(p),
+        signers: [triggerServer]
+      });
+      assert((await restingOrders()).length == 0, "Perp order not cancelled");
+
+      // the short position is already at the limit
+      await setMaxPerpPosition(positionSize);
+      await assertFails(
+        program.rpc.placePerpOrder(p.bumps, askPrice, new BN(-1), new BN(2), { postOnly: {} }, false, {
+          accounts: perpAccounts(p),
+          signers: [triggerServer]
+        }),
+        "PerpPositionLimitReached"
+      );
+      await setMaxPerpPosition(0);
+    });
+
+    it('Trigger orders', async () => {
+      const p = await strategyPdas();
+      const groupConfig = new Config(IDS).groups.find(g => g.publicKey.equals(mangoGroup));
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const cache = await group.loadCache(connection);
+      const perpMarketConfig = groupConfig.perpMarkets.find(m => m.publicKey.equals(mango.mangoMarket));
+      const perpMarket = await client.getPerpMarket(mango.mangoMarket, perpMarketConfig.baseDecimals, perpMarketConfig.quoteDecimals);
+      const [advancedOrders, _advancedOrdersBump] = await PublicKey.findProgramAddress([p.mangoAccount.toBuffer()], mangoProgram);
+      const triggerOrdersAccounts = (authority: PublicKey) => ({
+        strategyId: strategyId.publicKey,
+        authority,
+        strategyAccount: p.strategyAccount,
+        triggerDelegate: p.triggerDelegate,
+        mangoProgram,
+        mangoGroup,
+        mangoAccount: p.mangoAccount,
+        mangoCache: mango.mangoCache,
+        mangoMarket: mango.mangoMarket,
+        advancedOrders,
+        spotOpenOrders: p.spotOpenOrders,
+        systemProgram: SystemProgram.programId,
+      });
+      const listTriggerOrders = async () => (await program.simulate.listTriggerOrders(p.bumps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          advancedOrders,
+        },
+      })).events.filter(e => e.name === "TriggerOrderEvent");
+      // stop loss of the short far above the oracle price
+      const oraclePrice = cache.priceCache[perpMarketConfig.marketIndex].price;
+      const triggerPrice = oraclePrice.mul(I80F48.fromNumber(10)).getData();
+      const [price, _quantity] = perpMarket.uiToNativePriceQuantity(group.getPriceUi(perpMarketConfig.marketIndex, cache) * 11, 0);
+      const addTriggerOrder = (authority: anchor.web3.Keypair) =>
+        program.rpc.addTriggerOrder(p.bumps, new BN(1), price, { above: {} }, triggerPrice, new BN(3), {
+          accounts: triggerOrdersAccounts(authority.publicKey),
+          signers: [authority]
+        });
+
+      await program.rpc.initTriggerOrders(p.bumps, { accounts: triggerOrdersAccounts(owner.publicKey), signers: [owner] });
+      await assertFails(addTriggerOrder(anchor.web3.Keypair.generate()), "ConstraintRaw");
+      await addTriggerOrder(owner);
+      const orders = await listTriggerOrders();
+      assert(orders.length == 1 && orders[0].data.clientOrderId.eqn(3), "Trigger order not added");
+
+      await program.rpc.removeTriggerOrder(p.bumps, orders[0].data.orderIndex, {
+        accounts: triggerOrdersAccounts(owner.publicKey),
+        signers: [owner]
+      });
+      assert((await listTriggerOrders()).length == 0, "Trigger order not removed");
+    });
+
+    it('Stats', async () => {
+      const p = await strategyPdas();
+      const groupConfig = new Config(IDS).groups.find(g => g.publicKey.equals(mangoGroup));
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const cache = await group.loadCache(connection);
+      const perpMarketConfig = groupConfig.perpMarkets.find(m => m.publicKey.equals(mango.mangoMarket));
+      const perpMarket = await client.getPerpMarket(mango.mangoMarket, perpMarketConfig.baseDecimals, perpMarketConfig.quoteDecimals);
+      const strategyMangoAccount = await client.getMangoAccount(p.mangoAccount, serumDex);
+      const viewStats = async () => (await program.simulate.viewStats(p.bumps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+        },
+      })).events.find(e => e.name === "StatsEvent").data;
+
+      const { stats } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      assert(
+        stats.basePosition.eq(strategyMangoAccount.perpAccounts[perpMarketConfig.marketIndex].basePosition),
+        "Recorded perp position differs from Mango"
+      );
+      assert(stats.estimatedFees.gtn(0), "Taker fees of the hedge not recorded");
+      const statsEvent = await viewStats();
+      assert(statsEvent.estimatedFees.eq(stats.estimatedFees) && statsEvent.slippage.eq(stats.slippage), "Invalid StatsEvent");
+
+      // a resting order is not a trade
+      const [bidPrice, _bidQuantity] = perpMarket.uiToNativePriceQuantity(group.getPriceUi(perpMarketConfig.marketIndex, cache) * 0.5, 0);
+      await program.rpc.placePerpOrder(p.bumps, bidPrice, new BN(1), new BN(4), { postOnly: {} }, true, {
+        accounts: perpAccounts(p),
+        signers: [triggerServer]
+      });
+      const statsAfter = (await program.account.strategyAccount.fetch(p.strategyAccount)).stats;
+      assert(
+        statsAfter.estimatedFees.eq(stats.estimatedFees) && statsAfter.slippage.eq(stats.slippage) && statsAfter.basePosition.eq(stats.basePosition),
+        "Resting order recorded as a trade"
+      );
+      await program.rpc.cancelPerpOrders(p.bumps, null, {
+        accounts: cancelPerpOrdersAccounts(p),
+        signers: [triggerServer]
+      });
+    });
+
+    it('Share price history', async () => {
+      const p = await strategyPdas();
+      const snapshotAccounts = {
+        strategyId: strategyId.publicKey,
+        strategyAccount: p.strategyAccount,
+        navAccount: p.navAccount,
+        sharePriceHistory: p.sharePriceHistory,
+        strategyTokenMint: p.strategyTokenMint,
+        mangoProgram,
+        mangoGroup,
+        mangoAccount: p.mangoAccount,
+        mangoCache: mango.mangoCache,
+      };
+
+      // deposit and withdraw recorded the first snapshot
+      const history = await program.account.sharePriceHistory.fetch(p.sharePriceHistory);
+      assert(history.snapshots.length == 1 && history.next == 1, "Deposit snapshot not recorded");
+      const [first] = history.snapshots;
+      assert(first.sharePrice.gtn(0) && first.tvl.gtn(0), "Invalid snapshot");
+
+      // less than 6 hours after the last snapshot nothing is appended
+      const { events } = await program.simulate.snapshot(p.bumps, { accounts: snapshotAccounts });
+      assert(!events.some(e => e.name === "SharePriceEvent"), "Snapshot appended within the interval");
+      await program.rpc.snapshot(p.bumps, { accounts: snapshotAccounts });
+      const historyAfter = await program.account.sharePriceHistory.fetch(p.sharePriceHistory);
+      assert(historyAfter.snapshots.length == 1 && historyAfter.next == 1, "Snapshot appended within the interval");
+    });
+
+    it('Hedge config', async () => {
+      const p = await strategyPdas();
+      const setHedgeConfig = (targetHedgeRatioBps: number, deltaToleranceBps: number) =>
+        program.rpc.setHedgeConfig(p.bumps, targetHedgeRatioBps, deltaToleranceBps, {
+          accounts: ownerAccounts(p),
+          signers: [owner],
+        });
+      const checkDelta = () => program.rpc.checkDelta(p.bumps, true, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+        },
+      });
+
+      await assertFails(setHedgeConfig(10001, 100), "InvalidHedgeConfig");
+      // the perp short hedges the whole spot deposit
+      await setHedgeConfig(10000, 500);
+      await checkDelta();
+      await assertFails(adjustPositionPerp(p, -2), "DeltaOutOfBand");
+      await setHedgeConfig(5000, 100);
+      await assertFails(checkDelta(), "DeltaOutOfBand");
+      await setHedgeConfig(0, 0);
+    });
+
+    it('Mode', async () => {
+      const p = await strategyPdas();
+      await program.rpc.setMode(p.bumps, { reverse: {} }, { accounts: ownerAccounts(p), signers: [owner] });
+      // long spot and short perp are against the reverse mode, they can only be unwound
+      await assertFails(adjustPositionPerp(p, -1), "InvalidModeDirection");
+      await adjustPositionPerp(p, 1);
+
+      await program.rpc.setMode(p.bumps, { normal: {} }, { accounts: ownerAccounts(p), signers: [owner] });
+      await adjustPositionPerp(p, -1);
+    });
+
+    it('Min funding rate', async () => {
+      const p = await strategyPdas();
+      const setMinFundingRate = (minFundingRateBps: number | null) => program.rpc.setMinFundingRate(p.bumps, minFundingRateBps, {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+
+      // 1_000_000% annualised, below it perp orders are reduce only
+      await setMinFundingRate(100_000_000);
+      await assertFails(adjustPositionPerp(p, -1), "FundingRateTooLow");
+      await adjustPositionPerp(p, 1);
+
+      await setMinFundingRate(null);
+      await adjustPositionPerp(p, -1);
+    });
+
+    it('Circuit breaker', async () => {
+      const p = await strategyPdas();
+      const setMaxBookDeviation = (maxBookDeviationBps: number) => program.rpc.setMaxBookDeviation(p.bumps, maxBookDeviationBps, {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+      const tripCircuitBreaker = () => program.rpc.tripCircuitBreaker(p.bumps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoCache: mango.mangoCache,
+          mangoMarket: mango.mangoMarket,
+          mangoBids: mango.mangoBids,
+          mangoAsks: mango.mangoAsks,
+        },
+      });
+      const trippedAt = async () => (await program.account.strategyAccount.fetch(p.strategyAccount)).circuitBreakerTrippedAt;
+
+      await setMaxBookDeviation(10000);
+      await assertFails(tripCircuitBreaker(), "CircuitBreakerNotTripped");
+
+      // the book mid is practically never within 0.01% of the oracle price
+      await setMaxBookDeviation(1);
+      await tripCircuitBreaker();
+      assert((await trippedAt()).gtn(0), "Tripped circuit breaker not recorded");
+      await assertFails(adjustPositionPerp(p, 1), "CircuitBreakerTripped");
+
+      // the book is within the limit again, the cooldown still blocks perp orders
+      await setMaxBookDeviation(10000);
+      await assertFails(adjustPositionPerp(p, 1), "CircuitBreakerTripped");
+
+      await program.rpc.resetCircuitBreaker(p.bumps, { accounts: ownerAccounts(p), signers: [owner] });
+      assert((await trippedAt()).eqn(0), "Circuit breaker not cleared");
+      await adjustPositionPerp(p, 1);
+      await adjustPositionPerp(p, -1);
+      await setMaxBookDeviation(0);
+    });
+
+    it('Leverage', async () => {
+      const p = await strategyPdas();
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const cache = await group.loadCache(connection);
+      const { marketInfo } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      const { tvl } = await program.account.navAccount.fetch(p.navAccount);
+      const setMaxLeverage = (maxLeverageBps: number) => program.rpc.setMaxLeverage(p.bumps, maxLeverageBps, {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+      // spot worth the TVL on top of the hedged spot, leverage above 1x
+      const lotSize = marketInfo.spotMarketLotSize.toNumber() / 10 ** group.tokens[marketInfo.spotTokenIndex].decimals;
+      const lots = Math.ceil(tvl.toNumber() / 1_000000 / group.getPriceUi(marketInfo.spotTokenIndex, cache) / lotSize);
+
+      await assertFails(setMaxLeverage(30001), "InvalidLeverage");
+      await assertFails(adjustPositionSpot(p, lots), "LeverageTooHigh");
+
+      await setMaxLeverage(30000);
+      await adjustPositionSpot(p, lots);
+      await adjustPositionSpot(p, -lots);
+      await setMaxLeverage(0);
+    });
+
+    it('Deleverage', async () => {
+      const p = await strategyPdas();
+      const accounts = await keeperAccounts(p);
+      const setKeeperConfig = (config: typeof keeperConfig) => program.rpc.setKeeperConfig(p.bumps, config, {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+      const deleverage = () => program.rpc.deleverage(p.bumps, { accounts, signers: [owner] });
+
+      await assertFails(setKeeperConfig({ ...keeperConfig, cooldown: new BN(-1) }), "InvalidKeeperConfig");
+      await setKeeperConfig(keeperConfig);
+      await assertFails(deleverage(), "DeleverageNotAllowed");
+
+      // any health ratio is below 655%, reducing the positions by 25% can't improve it by 655%
+      await setKeeperConfig({ ...keeperConfig, deleverageHealthRatioBps: 65535, minHealthImprovementBps: 65535 });
+      const balanceBefore = await strategyTokenBalance(p);
+      await deleverage();
+      assert((await strategyTokenBalance(p)) == balanceBefore, "Bounty paid without the min health improvement");
+      await assertFails(deleverage(), "KeeperCooldown");
+      await setKeeperConfig(keeperConfig);
+    });
+
+    it('Rebalance', async () => {
+      const p = await strategyPdas();
+      const accounts = await keeperAccounts(p);
+      const setKeeperConfig = (config: typeof keeperConfig) => program.rpc.setKeeperConfig(p.bumps, config, {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+      const setHedgeConfig = (targetHedgeRatioBps: number) => program.rpc.setHedgeConfig(p.bumps, targetHedgeRatioBps, 0, {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+      const rebalancePublic = () => program.rpc.rebalancePublic(p.bumps, { accounts, signers: [owner] });
+
+      await assertFails(rebalancePublic(), "RebalanceNotAllowed");
+
+      // perp short 4 lots below the spot, drift far above 10%
+      await adjustPositionPerp(p, 4);
+      await setHedgeConfig(10000);
+      await setKeeperConfig({ ...keeperConfig, rebalanceDriftBps: 1000 });
+      const balanceBefore = await strategyTokenBalance(p);
+      await rebalancePublic();
+      assert((await strategyTokenBalance(p)) > balanceBefore, "Bounty not paid");
+      await assertFails(rebalancePublic(), "RebalanceNotAllowed");
+
+      await setKeeperConfig(keeperConfig);
+      await setHedgeConfig(0);
+    });
+
+    it('Mango accounts', async () => {
+      const p = await strategyPdas();
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const second = await mangoAccountPdas(p, accountNum + 1);
+      const transferUsdc = (from: typeof second, to: typeof second, amount: number, authority: anchor.web3.Keypair) =>
+        program.rpc.transferUsdc(p.bumps, new BN(amount), {
+          accounts: {
+            strategyId: strategyId.publicKey,
+            authority: authority.publicKey,
+            strategyAccount: p.strategyAccount,
+            mangoProgram,
+            mangoGroup,
+            fromMangoAccount: from.mangoAccount,
+            toMangoAccount: to.mangoAccount,
+            mangoCache: mango.mangoCache,
+            mangoRootBank: mango.mangoRootBank,
+            mangoNodeBank: mango.mangoNodeBank,
+            mangoVault: mango.mangoVault,
+            mangoSigner: mango.mangoSigner,
+            fromSpotOpenOrders: from.spotOpenOrders,
+            vaultTokenAccount: p.vaultTokenAccount,
+            tokenProgram: TOKEN_PROGRAM_ID,
+          },
+          signers: [authority]
+        });
+
+      await program.rpc.createMangoAccount(p.bumps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          owner: owner.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: second.mangoAccount,
+          systemProgram: SystemProgram.programId,
+        },
+        signers: [owner],
+      });
+      const { mangoAccountCount } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      assert(mangoAccountCount.eqn(accountNum + 1), "Mango account not counted");
+
+      await assertFails(transferUsdc(p, second, 1_000000, anchor.web3.Keypair.generate()), "ConstraintRaw");
+      await transferUsdc(p, second, 1_000000, owner);
+      const cache = await group.loadCache(connection);
+      const secondAccount = await client.getMangoAccount(second.mangoAccount, serumDex);
+      const usdcIndex = group.getTokenIndex(usdcMint);
+      assert(secondAccount.getNativeDeposit(cache.rootBankCache[usdcIndex], usdcIndex).toNumber() >= 999999, "USDC not transferred");
+      // deposit index rounding may leave less than the full amount
+      await transferUsdc(second, p, 999999, triggerServer);
+    });
+
+    it('Registry', async () => {
+      const p = await strategyPdas();
+      const { registryIndex } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      const [registryPage, _registryPageBump] = await PublicKey.findProgramAddress(
+        [utf8.encode("registry"), registryIndex.divn(64).toBuffer('le', 8)],
+        program.programId
+      );
+      const registryEntry = async () => (await program.account.registryPage.fetch(registryPage)).entries[registryIndex.modn(64)];
+      const deprecateStrategy = (signer: anchor.web3.Keypair) => program.rpc.deprecateStrategy(p.bumps, {
+        accounts: { strategyId: strategyId.publicKey, owner: signer.publicKey, strategyAccount: p.strategyAccount, registryPage },
+        signers: [signer],
+      });
+
+      const entry = await registryEntry();
+      assert(entry.strategyId.equals(strategyId.publicKey), "Strategy not registered");
+      assert(entry.owner.equals(owner.publicKey) && entry.mangoGroup.equals(mangoGroup), "Wrong registry entry");
+      assert(!entry.deprecated && !entry.closed, "New strategy listed as deprecated");
+
+      await assertFails(deprecateStrategy(anchor.web3.Keypair.generate()), "ConstraintAddress");
+      await deprecateStrategy(owner);
+      assert((await registryEntry()).deprecated, "Strategy not deprecated");
+    });
+
+    it('Batched deposits', async () => {
+      const p = await strategyPdas();
+      const group = await new MangoClient(connection, mangoProgram).getMangoGroup(mangoGroup);
+      const usdcTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, owner.publicKey);
+      const strategyTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, owner.publicKey);
+      const [pendingDeposit, _pendingDepositBump] = await PublicKey.findProgramAddress(
+        [strategyId.publicKey.toBuffer(), utf8.encode("pending_deposit"), owner.publicKey.toBuffer()],
+        program.programId
+      );
+      const extra = await extraMangoAccounts(p);
+      const limits = { isSigner: false, isWritable: true, pubkey: limitsAccount.publicKey };
+      const setDepositEpoch = (depositEpoch: number) => program.rpc.setDepositEpoch(p.bumps, new BN(depositEpoch), {
+        accounts: ownerAccounts(p),
+        signers: [owner],
+      });
+      const queueDeposit = (amount: number) => program.rpc.queueDeposit(p.bumps, new BN(amount), {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          owner: owner.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          vaultTokenMint: usdcMint,
+          depositQueue: p.depositQueue,
+          pendingVaultTokenAccount: p.pendingVaultTokenAccount,
+          depositTokenAccount: usdcTokenAccount.address,
+          pendingDeposit,
+          depositReceipt: p.depositReceipt,
+          userPosition: p.userPosition,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+          rent: anchor.web3.SYSVAR_RENT_PUBKEY,
+        },
+        remainingAccounts: [limits, ...extra],
+        signers: [owner],
+      });
+      const { marketInfo } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      const processDepositsAccounts = {
+        keeper: await keeperAccounts(p),
+        depositQueue: p.depositQueue,
+        pendingVaultTokenAccount: p.pendingVaultTokenAccount,
+        sharePriceHistory: p.sharePriceHistory,
+        oracle: group.oracles[marketInfo.perpMarketIndex],
+      };
+      const processDeposits = () => program.rpc.processDeposits(p.bumps, {
+        accounts: processDepositsAccounts,
+        remainingAccounts: [
+          ...extra,
+          { isSigner: false, isWritable: true, pubkey: pendingDeposit },
+          { isSigner: false, isWritable: true, pubkey: strategyTokenAccount.address },
+          { isSigner: false, isWritable: true, pubkey: p.userPosition },
+        ],
+        signers: [owner],
+      });
+      const deposit = () => program.rpc.deposit(p.bumps, new BN(1_000000), new BN(0), {
+        accounts: {
+          owner: owner.publicKey,
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          mangoRootBank: mango.mangoRootBank,
+          mangoNodeBank: mango.mangoNodeBank,
+          mangoVault: mango.mangoVault,
+          vaultTokenAccount: p.vaultTokenAccount,
+          depositTokenAccount: usdcTokenAccount.address,
+          strategyTokenMint: p.strategyTokenMint,
+          strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt: p.depositReceipt,
+          sharePriceHistory: p.sharePriceHistory,
+          userPosition: p.userPosition,
+          navAccount: p.navAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        },
+        remainingAccounts: [limits, ...extra],
+        signers: [owner],
+      });
+
+      await assertFails(queueDeposit(1_000000), "BatchedDepositsDisabled");
+      await setDepositEpoch(60);
+      await assertFails(deposit(), "BatchedDepositsOnly");
+
+      await queueDeposit(5_000000);
+      assert((await program.account.pendingDeposit.fetch(pendingDeposit)).amount.eqn(5_000000), "Deposit not queued");
+      const balanceBefore = await strategyTokenBalance(p);
+      await processDeposits();
+      assert((await strategyTokenBalance(p)) > balanceBefore, "Shares not minted");
+      assert((await program.account.pendingDeposit.fetch(pendingDeposit)).amount.isZero(), "Pending deposit not cleared");
+      assert((await program.account.depositQueue.fetch(p.depositQueue)).pendingAmount.isZero(), "Queue not cleared");
+
+      const depositedBefore = (await program.account.limitsAccount.fetch(limitsAccount.publicKey)).whitelist
+        .find(w => w.key.equals(owner.publicKey)).deposit;
+      await queueDeposit(1_000000);
+      await assertFails(processDeposits(), "DepositEpochNotElapsed");
+      await program.rpc.cancelDeposit(p.bumps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          owner: owner.publicKey,
+          strategyAccount: p.strategyAccount,
+          depositQueue: p.depositQueue,
+          pendingVaultTokenAccount: p.pendingVaultTokenAccount,
+          pendingDeposit,
+          depositTokenAccount: usdcTokenAccount.address,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        remainingAccounts: [limits],
+        signers: [owner],
+      });
+      assert((await program.account.depositQueue.fetch(p.depositQueue)).pendingAmount.isZero(), "Deposit not cancelled");
+      const { whitelist } = await program.account.limitsAccount.fetch(limitsAccount.publicKey);
+      const limit = whitelist.find(w => w.key.equals(owner.publicKey));
+      assert(limit.deposit.eq(depositedBefore), "Cancelled deposit still counted in the whitelist limit");
+
+      await setDepositEpoch(0);
+    });
+
+    it('User position', async () => {
+      const p = await strategyPdas();
+      const usdcTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, owner.publicKey);
+      const strategyTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, owner.publicKey);
+      const remainingAccounts = [{ isSigner: false, isWritable: true, pubkey: limitsAccount.publicKey }, ...await extraMangoAccounts(p)];
+      const deposit = (amount: number, minSharesOut: BN) => program.rpc.deposit(p.bumps, new BN(amount), minSharesOut, {
+        accounts: {
+          owner: owner.publicKey,
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          mangoRootBank: mango.mangoRootBank,
+          mangoNodeBank: mango.mangoNodeBank,
+          mangoVault: mango.mangoVault,
+          vaultTokenAccount: p.vaultTokenAccount,
+          depositTokenAccount: usdcTokenAccount.address,
+          strategyTokenMint: p.strategyTokenMint,
+          strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt: p.depositReceipt,
+          sharePriceHistory: p.sharePriceHistory,
+          userPosition: p.userPosition,
+          navAccount: p.navAccount,
+          tokenProgram: TOKEN_PROGRAM_ID,
+          systemProgram: SystemProgram.programId,
+        },
+        remainingAccounts,
+        signers: [owner],
+      });
+      const withdraw = (shares: bigint) => program.rpc.withdraw(p.bumps, new BN(shares.toString()), new BN(0), {
+        accounts: {
+          owner: owner.publicKey,
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          mangoRootBank: mango.mangoRootBank,
+          mangoNodeBank: mango.mangoNodeBank,
+          mangoVault: mango.mangoVault,
+          mangoSigner: mango.mangoSigner,
+          spotOpenOrders: p.spotOpenOrders,
+          withdrawTokenAccount: usdcTokenAccount.address,
+          strategyTokenMint: p.strategyTokenMint,
+          strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt: p.depositReceipt,
+          sharePriceHistory: p.sharePriceHistory,
+          userPosition: p.userPosition,
+          navAccount: p.navAccount,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        remainingAccounts,
+        signers: [owner],
+      });
+
+      const before = await program.account.userPosition.fetch(p.userPosition);
+      assert(before.owner.equals(owner.publicKey), "Wrong position owner");
+      await assertFails(deposit(1_000000, new BN("18446744073709551615")), "SlippageExceeded");
+      const unchanged = await program.account.userPosition.fetch(p.userPosition);
+      assert(unchanged.totalDeposited.eq(before.totalDeposited) && unchanged.shares.eq(before.shares), "Failed deposit recorded");
+
+      const balanceBefore = await strategyTokenBalance(p);
+      await deposit(1_000000, new BN(0));
+      const minted = (await strategyTokenBalance(p)) - balanceBefore;
+      const afterDeposit = await program.account.userPosition.fetch(p.userPosition);
+      assert(afterDeposit.totalDeposited.sub(before.totalDeposited).eqn(1_000000), "Deposit not recorded");
+      assert(afterDeposit.shares.sub(before.shares).eq(new BN(minted.toString())), "Shares not recorded");
+
+      // keeper bounties are not in the position, they are withdrawn first
+      const untracked = (await strategyTokenBalance(p)) - BigInt(afterDeposit.shares.toString());
+      const tracked = minted > untracked ? minted - untracked : BigInt(0);
+      await withdraw(minted);
+      const afterWithdraw = await program.account.userPosition.fetch(p.userPosition);
+      assert(afterWithdraw.shares.eq(afterDeposit.shares.sub(new BN(tracked.toString()))), "Withdrawn shares not recorded");
+      assert(afterWithdraw.totalWithdrawn.gt(before.totalWithdrawn), "Withdrawal not recorded");
+    });
+
+    it('NAV account', async () => {
+      const p = await strategyPdas();
+      const before = await program.account.navAccount.fetch(p.navAccount);
+      await program.rpc.snapshot(p.bumps, {
+        accounts: {
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          navAccount: p.navAccount,
+          sharePriceHistory: p.sharePriceHistory,
+          strategyTokenMint: p.strategyTokenMint,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+        },
+        remainingAccounts: await extraMangoAccounts(p),
+      });
+
+      const nav = await program.account.navAccount.fetch(p.navAccount);
+      const mint = await getMint(connection, p.strategyTokenMint);
+      assert(nav.strategyAccount.equals(p.strategyAccount), "Wrong NAV strategy");
+      assert(nav.supply.eq(new BN(mint.supply.toString())), "NAV supply not refreshed");
+      assert(nav.sharePrice.gtn(0) && nav.tvl.gtn(0), "Invalid NAV");
+      assert(nav.lastUpdateSlot.gt(before.lastUpdateSlot), "NAV not refreshed by the snapshot");
+
+      await assertFails(
+        program.rpc.createNavAccount(p.bumps, {
+          accounts: {
+            strategyId: strategyId.publicKey,
+            payer: owner.publicKey,
+            strategyAccount: p.strategyAccount,
+            navAccount: p.navAccount,
+            systemProgram: SystemProgram.programId,
+          },
+          signers: [owner],
+        }),
+        "already in use"
+      );
+    });
+
+    it('Withdraw slippage bound', async () => {
+      const p = await strategyPdas();
+      const user = await userAccounts(p);
+      const withdraw = async (minVaultTokensOut: BN) => program.rpc.withdraw(p.bumps, new BN(100000), minVaultTokensOut, {
+        accounts: user.withdraw,
+        remainingAccounts: await userRemainingAccounts(p),
+        signers: [owner],
+      });
+
+      const sharesBefore = await tokenBalance(user.strategyTokenAccount);
+      const usdcBefore = await tokenBalance(user.usdcTokenAccount);
+      // 0.1 strategy tokens can't be worth 1000 USDC
+      await assertFails(withdraw(new BN(1000_000000)), "SlippageExceeded");
+      assert(await tokenBalance(user.strategyTokenAccount) == sharesBefore, "Shares burnt by the failed withdrawal");
+      assert(await tokenBalance(user.usdcTokenAccount) == usdcBefore, "USDC paid by the failed withdrawal");
+
+      await withdraw(new BN(1));
+      assert(await tokenBalance(user.strategyTokenAccount) == sharesBefore - BigInt(100000), "Withdrawal within the bound failed");
+    });
+
+    it('Rounding', async () => {
+      const p = await strategyPdas();
+      const user = await userAccounts(p);
+      const remainingAccounts = await userRemainingAccounts(p);
+      const depositAmount = new BN(1_000000);
+      const withdrawShares = new BN(500000);
+      // one transaction, so both are priced off the same Mango cache
+      const tx = new anchor.web3.Transaction()
+        .add(program.instruction.deposit(p.bumps, depositAmount, new BN(0), { accounts: user.deposit, remainingAccounts }))
+        .add(program.instruction.withdraw(p.bumps, withdrawShares, new BN(0), { accounts: user.withdraw, remainingAccounts }));
+      const events = await txEvents(await program.provider.send(tx, [owner]));
+
+      const deposited = events.find(e => e.name === "DepositEvent").data;
+      const withdrawn = events.find(e => e.name === "WithdrawEvent").data;
+      assert(deposited.strategyTokenAmount.gtn(0), "No shares minted");
+      // shares are rounded down on mint and vault tokens on burn, the round trip can't gain
+      assert(
+        withdrawn.vaultTokenAmount.add(withdrawn.fee).mul(deposited.strategyTokenAmount)
+          .lte(deposited.vaultTokenAmount.mul(withdrawShares)),
+        "Depositor gained through rounding"
+      );
+    });
+
+    it('Fees', async () => {
+      const p = await strategyPdas();
+      const user = await userAccounts(p);
+      const remainingAccounts = await userRemainingAccounts(p);
+      const setFees = (depositFeeBps: number, withdrawFeeBps: number) =>
+        program.rpc.setFees(p.bumps, depositFeeBps, withdrawFeeBps, { accounts: ownerAccounts(p), signers: [owner] });
+      const { depositFeeBps, withdrawFeeBps } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      await setFees(50, 30);
+
+      const usdcBeforeDeposit = await tokenBalance(user.usdcTokenAccount);
+      const sharesBeforeDeposit = await tokenBalance(user.strategyTokenAccount);
+      const deposited = (await txEvents(await program.rpc.deposit(p.bumps, new BN(1_000000), new BN(0), {
+        accounts: user.deposit,
+        remainingAccounts,
+        signers: [owner],
+      }))).find(e => e.name === "DepositEvent").data;
+      assert(deposited.fee.eq(feeOf(new BN(1_000000), 50)), "Wrong deposit fee");
+      // the fee stays in the strategy, only the shares are reduced
+      assert(usdcBeforeDeposit - await tokenBalance(user.usdcTokenAccount) == BigInt(1_000000), "Deposit fee sent elsewhere");
+      assert(
+        await tokenBalance(user.strategyTokenAccount) - sharesBeforeDeposit == BigInt(deposited.strategyTokenAmount.toString()),
+        "Minted shares differ from the event"
+      );
+
+      const usdcBeforeWithdraw = await tokenBalance(user.usdcTokenAccount);
+      const withdrawn = (await txEvents(await program.rpc.withdraw(p.bumps, new BN(500000), new BN(0), {
+        accounts: user.withdraw,
+        remainingAccounts,
+        signers: [owner],
+      }))).find(e => e.name === "WithdrawEvent").data;
+      assert(withdrawn.fee.eq(feeOf(withdrawn.vaultTokenAmount.add(withdrawn.fee), 30)), "Wrong withdraw fee");
+      assert(
+        await tokenBalance(user.usdcTokenAccount) - usdcBeforeWithdraw == BigInt(withdrawn.vaultTokenAmount.toString()),
+        "Withdraw fee paid out"
+      );
+
+      await setFees(depositFeeBps, withdrawFeeBps);
+    });
+
+    it('Holding period', async () => {
+      const p = await strategyPdas();
+      const user = await userAccounts(p);
+      const remainingAccounts = await userRemainingAccounts(p);
+      const setMinHoldingPeriod = (minHoldingPeriod: number, earlyExitFeeBps: number) =>
+        program.rpc.setMinHoldingPeriod(p.bumps, new BN(minHoldingPeriod), earlyExitFeeBps, { accounts: ownerAccounts(p), signers: [owner] });
+
+      await setMinHoldingPeriod(3600, 100);
+      await program.rpc.deposit(p.bumps, new BN(1_000000), new BN(0), { accounts: user.deposit, remainingAccounts, signers: [owner] });
+      await assertFails(
+        program.rpc.withdraw(p.bumps, new BN(100000), new BN(0), { accounts: user.withdraw, remainingAccounts, signers: [owner] }),
+        "HoldingPeriodNotElapsed"
+      );
+
+      // the holder is not in the whitelist
+      if (!enableLimits) {
+        // shares received by transfer have no deposit of the holder behind them, they pay the full early exit fee
+        const holder = anchor.web3.Keypair.generate();
+        await sendAndConfirmTransaction(connection, new Transaction().add(SystemProgram.transfer({
+          fromPubkey: owner.publicKey,
+          toPubkey: holder.publicKey,
+          lamports: LAMPORTS_PER_SOL / 100,
+        })), [owner]);
+        const holderAccounts = await userAccounts(p, holder.publicKey);
+        await transfer(connection, owner, user.strategyTokenAccount, holderAccounts.strategyTokenAccount, owner, 200000);
+
+        const withdrawn = (await txEvents(await program.rpc.withdraw(p.bumps, new BN(200000), new BN(0), {
+          accounts: holderAccounts.withdraw,
+          remainingAccounts,
+          signers: [holder],
+        }))).find(e => e.name === "WithdrawEvent").data;
+        const { withdrawFeeBps } = await program.account.strategyAccount.fetch(p.strategyAccount);
+        const value = withdrawn.vaultTokenAmount.add(withdrawn.fee);
+        assert(withdrawn.fee.eq(feeOf(value, withdrawFeeBps).add(feeOf(value, 100))), "Wrong early exit fee");
+        assert(await tokenBalance(holderAccounts.usdcTokenAccount) == BigInt(withdrawn.vaultTokenAmount.toString()), "Holder not paid");
+      }
+
+      await setMinHoldingPeriod(0, 0);
+    });
+
+    // runs last: the timelock delay is a tuple variant the TS client can't propose, so it stays enabled
+    it('Timelock', async () => {
+      const p = await strategyPdas();
+      const changeAccounts = {
+        strategyId: strategyId.publicKey,
+        owner: owner.publicKey,
+        strategyAccount: p.strategyAccount,
+        pendingChange: p.pendingChange,
+        systemProgram: SystemProgram.programId,
+      };
+      const { depositFeeBps, withdrawFeeBps } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      const change = { fees: { depositFeeBps: depositFeeBps + 1, withdrawFeeBps } };
+      const proposeChange = () => program.rpc.proposeChange(p.bumps, change, { accounts: changeAccounts, signers: [owner] });
+      const executeChange = () => program.rpc.executeChange(p.bumps, { accounts: changeAccounts, signers: [owner] });
+
+      await program.rpc.setTimelockDelay(p.bumps, new BN(5), { accounts: ownerAccounts(p), signers: [owner] });
+      await assertFails(
+        program.rpc.setFees(p.bumps, depositFeeBps + 1, withdrawFeeBps, { accounts: ownerAccounts(p), signers: [owner] }),
+        "TimelockRequired"
+      );
+
+      await proposeChange();
+      await assertFails(executeChange(), "TimelockNotElapsed");
+      const { systemProgram: _systemProgram, ...cancelAccounts } = changeAccounts;
+      await program.rpc.cancelChange(p.bumps, { accounts: cancelAccounts, signers: [owner] });
+      assert((await connection.getAccountInfo(p.pendingChange)) === null, "Change not cancelled");
+
+      await proposeChange();
+      await new Promise(resolve => setTimeout(resolve, 7000));
+      await executeChange();
+      const strategyAccount = await program.account.strategyAccount.fetch(p.strategyAccount);
+      assert(strategyAccount.depositFeeBps == depositFeeBps + 1, "Change not executed");
+      assert((await connection.getAccountInfo(p.pendingChange)) === null, "Pending change not closed");
+    });
+
+    it('Close strategy', async () => {
+      const p = await strategyPdas();
+      const groupConfig = new Config(IDS).groups.find(g => g.publicKey.equals(mangoGroup));
+      const client = new MangoClient(connection, mangoProgram);
+      const group = await client.getMangoGroup(mangoGroup);
+      const rootBanks = await group.loadRootBanks(connection);
+      const perpMarketConfig = groupConfig.perpMarkets.find(m => m.publicKey.equals(mango.mangoMarket));
+      const perpMarket = await client.getPerpMarket(mango.mangoMarket, perpMarketConfig.baseDecimals, perpMarketConfig.quoteDecimals);
+      const mngoIndex = group.getTokenIndex(groupConfig.tokens.find(t => t.symbol === "MNGO").mintKey);
+      const baseMint = group.tokens[2].mint;
+      const [dustAccount, _dustBump] = await PublicKey.findProgramAddress(
+        [mangoGroup.toBytes(), utf8.encode("DustAccount")],
+        mangoProgram
+      );
+
+      const usdcTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, owner.publicKey);
+      const baseTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, baseMint, owner.publicKey);
+      const mngoTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, group.tokens[mngoIndex].mint, owner.publicKey);
+      const closeMangoAccountAccounts = {
+        strategyId: strategyId.publicKey,
+        owner: owner.publicKey,
+        strategyAccount: p.strategyAccount,
+        strategyTokenMint: p.strategyTokenMint,
+        mangoProgram,
+        mangoGroup,
+        mangoAccount: p.mangoAccount,
+        mangoCache: mango.mangoCache,
+        mangoSigner: mango.mangoSigner,
+        dustAccount,
+        mangoMarket: mango.mangoMarket,
+        mngoPerpVault: perpMarket.mngoVault,
+        mangoRootBank: mango.mangoRootBank,
+        mangoNodeBank: mango.mangoNodeBank,
+        mangoVault: mango.mangoVault,
+        ownerTokenAccount: usdcTokenAccount.address,
+        spotBaseRootBank: mango.spotBaseRootBank,
+        spotBaseNodeBank: mango.spotBaseNodeBank,
+        spotBaseVault: mango.spotBaseVault,
+        ownerBaseTokenAccount: baseTokenAccount.address,
+        mngoRootBank: rootBanks[mngoIndex].publicKey,
+        mngoNodeBank: rootBanks[mngoIndex].nodeBankAccounts[0].publicKey,
+        mngoVault: rootBanks[mngoIndex].nodeBankAccounts[0].vault,
+        ownerMngoTokenAccount: mngoTokenAccount.address,
+        serumDex,
+        spotMarket,
+        spotOpenOrders: p.spotOpenOrders,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      };
+      // advanced orders were created by initTriggerOrders, MNGO open orders by harvestMngo
+      const [advancedOrders, _advancedOrdersBump] = await PublicKey.findProgramAddress([p.mangoAccount.toBuffer()], mangoProgram);
+      const mngoSpotAccounts = await mngoSpot(p.mangoAccount);
+      const closeMangoAccountRemaining = [
+        { isSigner: false, isWritable: true, pubkey: advancedOrders },
+        { isSigner: false, isWritable: true, pubkey: mngoSpotAccounts.market.publicKey },
+        { isSigner: false, isWritable: true, pubkey: mngoSpotAccounts.openOrders },
+      ];
+      const { registryIndex } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      const [registryPage, _registryPageBump] = await PublicKey.findProgramAddress(
+        [utf8.encode("registry"), registryIndex.divn(64).toBuffer('le', 8)],
+        program.programId
+      );
+      const closeStrategyAccounts = {
+        strategyId: strategyId.publicKey,
+        owner: owner.publicKey,
+        strategyAccount: p.strategyAccount,
+        registryPage,
+        sharePriceHistory: p.sharePriceHistory,
+        navAccount: p.navAccount,
+        depositQueue: p.depositQueue,
+        pendingVaultTokenAccount: p.pendingVaultTokenAccount,
+        pendingChange: p.pendingChange,
+        triggerDelegate: p.triggerDelegate,
+        vaultTokenAccount: p.vaultTokenAccount,
+        strategyTokenMint: p.strategyTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+      };
+
+      await assertFails(
+        program.rpc.closeMangoAccount(p.bumps, { accounts: closeMangoAccountAccounts, remainingAccounts: closeMangoAccountRemaining, signers: [owner] }),
+        "StrategyNotEmpty"
+      );
+      await assertFails(
+        program.rpc.closeStrategy(p.bumps, { accounts: closeStrategyAccounts, signers: [owner] }),
+        "StrategyNotEmpty"
+      );
+      assert((await connection.getAccountInfo(p.strategyAccount)) !== null, "Strategy closed with shares outstanding");
+
+      // unwind the positions left by the tests above (keepers traded them) and withdraw all strategy tokens
+      const positions = await client.getMangoAccount(p.mangoAccount, serumDex);
+      const { marketInfo } = await program.account.strategyAccount.fetch(p.strategyAccount);
+      const perpPosition = positions.perpAccounts[marketInfo.perpMarketIndex].basePosition.toNumber();
+      const spotDeposit = positions.getNativeDeposit((await group.loadCache(connection)).rootBankCache[marketInfo.spotTokenIndex], marketInfo.spotTokenIndex);
+      const spotLots = spotDeposit.toNumber() / marketInfo.spotMarketLotSize.toNumber();
+      if (perpPosition != 0) {
+        await program.rpc.adjustPositionPerp(p.bumps, new BN(-perpPosition), true, {
+          accounts: perpAccounts(p),
+          remainingAccounts: await extraMangoAccounts(p),
+          signers: [triggerServer]
+        });
+      }
+      if (spotLots >= 1) {
+        await adjustPositionSpot(p, -Math.floor(spotLots));
+      }
+      const strategyTokenAccount = await getOrCreateAssociatedTokenAccount(connection, owner, p.strategyTokenMint, owner.publicKey);
+      await program.rpc.withdraw(p.bumps, new BN(strategyTokenAccount.amount.toString()), new BN(0), {
+        accounts: {
+          owner: owner.publicKey,
+          strategyId: strategyId.publicKey,
+          strategyAccount: p.strategyAccount,
+          mangoProgram,
+          mangoGroup,
+          mangoAccount: p.mangoAccount,
+          mangoCache: mango.mangoCache,
+          mangoRootBank: mango.mangoRootBank,
+          mangoNodeBank: mango.mangoNodeBank,
+          mangoVault: mango.mangoVault,
+          mangoSigner: mango.mangoSigner,
+          spotOpenOrders: p.spotOpenOrders,
+          withdrawTokenAccount: usdcTokenAccount.address,
+          strategyTokenMint: p.strategyTokenMint,
+          strategyTokenAccount: strategyTokenAccount.address,
+          depositReceipt: p.depositReceipt,
+          sharePriceHistory: p.sharePriceHistory,
+          userPosition: p.userPosition,
+          navAccount: p.navAccount,
+          systemProgram: SystemProgram.programId,
+          tokenProgram: TOKEN_PROGRAM_ID,
+        },
+        remainingAccounts: [{ isSigner: false, isWritable: true, pubkey: limitsAccount.publicKey }, ...await extraMangoAccounts(p)],
+        signers: [owner],
+      });
+      // Mango requires a zero perp quote position to close the account
+      const cache = await group.loadCache(connection);
+      const strategyMangoAccount = await client.getMangoAccount(p.mangoAccount, serumDex);
+      await client.settlePnl(group, cache, strategyMangoAccount, perpMarket, rootBanks[group.getTokenIndex(usdcMint)], cache.priceCache[2].price, owner);
+
+      await assertFails(
+        program.rpc.closeStrategy(p.bumps, { accounts: closeStrategyAccounts, signers: [owner] }),
+        "MangoAccountNotClosed"
+      );
+
+      const usdcBalanceBefore = (await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, owner.publicKey)).amount;
+      // the additional account is closed first
+      const second = await mangoAccountPdas(p, accountNum + 1);
+      await program.rpc.closeMangoAccount(p.bumps, {
+        accounts: { ...closeMangoAccountAccounts, mangoAccount: second.mangoAccount, spotOpenOrders: second.spotOpenOrders },
+        signers: [owner]
+      });
+      await program.rpc.closeMangoAccount(p.bumps, { accounts: closeMangoAccountAccounts, remainingAccounts: closeMangoAccountRemaining, signers: [owner] });
+      await program.rpc.closeStrategy(p.bumps, { accounts: closeStrategyAccounts, signers: [owner] });
+
+      const usdcBalanceAfter = (await getOrCreateAssociatedTokenAccount(connection, owner, usdcMint, owner.publicKey)).amount;
+      assert(usdcBalanceAfter >= usdcBalanceBefore, "USDC left in the mango account not withdrawn");
+      assert((await connection.getAccountInfo(second.mangoAccount)) === null, "Additional Mango account not closed");
+      assert((await connection.getAccountInfo(p.mangoAccount)) === null, "Mango account not closed");
+      assert((await connection.getAccountInfo(p.strategyAccount)) === null, "Strategy account not closed");
+      assert((await connection.getAccountInfo(p.sharePriceHistory)) === null, "Share price history not closed");
+      assert((await connection.getAccountInfo(p.navAccount)) === null, "NAV account not closed");
+      assert((await connection.getAccountInfo(p.triggerDelegate)) === null, "Trigger delegate not drained");
+      const { entries } = await program.account.registryPage.fetch(registryPage);
+      const entry = entries.find((e) => e.strategyId.equals(strategyId.publicKey));
+      assert(entry.deprecated && entry.closed, "Registry entry not marked closed");
+    });
   }
 });